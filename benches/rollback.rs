@@ -0,0 +1,189 @@
+//! Baseline numbers for the rollback path as the physics world grows.
+//!
+//! Run with `cargo bench`, or `cargo bench -- resimulate` for a single group.
+
+use bevy::{ecs::schedule::Stage, transform::TransformPlugin};
+use bevy_ggrs_rapier_example::{bullet::BulletBundle, prelude::*, rapier_configuration};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::SmallRng, SeedableRng};
+
+const WALLS: [usize; 3] = [100, 1000, 4000];
+const BULLETS: [usize; 3] = [0, 100, 1000];
+
+/// A headless world with `walls` wall tiles laid out in a square grid and
+/// `bullets` bullets flying around in between them, stepped once so Rapier
+/// has picked everything up.
+fn build_world(walls: usize, bullets: usize) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(
+            RapierPhysicsPlugin::<NoUserData>::default()
+                .with_physics_scale(100.)
+                .with_default_system_setup(false),
+        )
+        .insert_resource(rapier_configuration())
+        .insert_resource(RollbackIdProvider::default())
+        .insert_resource(CurrentFrame::default())
+        .insert_resource(ConfirmedFrame::default())
+        .insert_resource(FrameHashes::default())
+        .insert_resource(PhysicsRollbackState::default())
+        .insert_resource(PhysicsSnapshots::default());
+
+    let side = (walls as f32).sqrt().ceil() as usize;
+    let spacing = 3.0 * TILE_SIZE as f32;
+    for i in 0..walls {
+        let transform = Transform::from_xyz(
+            (i % side) as f32 * spacing,
+            (i / side) as f32 * spacing,
+            0.0,
+        );
+        app.world.spawn((
+            transform,
+            GlobalTransform::from(transform),
+            RigidBody::Fixed,
+            Collider::cuboid(32.0, 32.0),
+            CollisionGroups::new(COL_TERRAIN, Group::ALL),
+        ));
+    }
+
+    let weapons = Weapons::parse(include_bytes!("../assets/weapons.ron"))
+        .expect("assets/weapons.ron is broken");
+    let mut rng = SmallRng::seed_from_u64(0);
+    let extent = side as f32 * spacing;
+    for _ in 0..bullets {
+        let position = Vec2::new(rng.gen_range(0.0..extent), rng.gen_range(0.0..extent));
+        let angle = rng.gen_range(-PI..PI);
+        let id = Rollback::new(app.world.resource_mut::<RollbackIdProvider>().next_id());
+        app.world
+            .spawn(BulletBundle::new(
+                0,
+                &weapons,
+                0,
+                position,
+                angle,
+                Handle::default(),
+            ))
+            .insert(id);
+    }
+
+    // Propagate transforms so Rapier sees everything where it was spawned
+    app.update();
+    let mut schedule = physics_schedule();
+    schedule.run(&mut app.world);
+    app
+}
+
+/// The physics half of our rollback schedule, plus the snapshot save
+fn physics_schedule() -> Schedule {
+    Schedule::default()
+        .with_stage(
+            ROLLBACK_SYSTEMS,
+            SystemStage::single_threaded().with_system(update_current_frame),
+        )
+        .with_stage_after(
+            ROLLBACK_SYSTEMS,
+            PhysicsStages::SyncBackend,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::SyncBackend),
+            ),
+        )
+        .with_stage_after(
+            PhysicsStages::SyncBackend,
+            PhysicsStages::StepSimulation,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::StepSimulation),
+            ),
+        )
+        .with_stage_after(
+            PhysicsStages::StepSimulation,
+            PhysicsStages::Writeback,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::Writeback),
+            ),
+        )
+        .with_stage_after(
+            PhysicsStages::Writeback,
+            CHECKSUM_SYSTEMS,
+            SystemStage::parallel().with_system(save_rapier_context),
+        )
+}
+
+fn sizes() -> impl Iterator<Item = (usize, usize)> {
+    WALLS
+        .into_iter()
+        .flat_map(|walls| BULLETS.into_iter().map(move |bullets| (walls, bullets)))
+}
+
+fn name(walls: usize, bullets: usize) -> String {
+    format!("{}w/{}b", walls, bullets)
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    for (walls, bullets) in sizes() {
+        let app = build_world(walls, bullets);
+        let rapier = app.world.resource::<RapierContext>();
+        group.bench_function(BenchmarkId::from_parameter(name(walls, bullets)), |b| {
+            b.iter(|| bincode::serialize(rapier).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn deserialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deserialize");
+    for (walls, bullets) in sizes() {
+        let app = build_world(walls, bullets);
+        let bytes = bincode::serialize(app.world.resource::<RapierContext>()).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name(walls, bullets)), |b| {
+            b.iter(|| bincode::deserialize::<RapierContext>(&bytes).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn checksum(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fletcher16");
+    for (walls, bullets) in sizes() {
+        let app = build_world(walls, bullets);
+        let bytes = bincode::serialize(app.world.resource::<RapierContext>()).unwrap();
+        group.bench_function(BenchmarkId::from_parameter(name(walls, bullets)), |b| {
+            b.iter(|| fletcher16(&bytes))
+        });
+    }
+    group.finish();
+}
+
+/// Roll back to the first snapshot and resimulate a full prediction window,
+/// which is the worst case a single GGRS update can ask of us
+fn resimulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("resimulate");
+    group.sample_size(10);
+    for (walls, bullets) in sizes() {
+        let mut app = build_world(walls, bullets);
+        let mut schedule = physics_schedule();
+        let start = app.world.resource::<CurrentFrame>().0;
+
+        group.bench_function(BenchmarkId::from_parameter(name(walls, bullets)), |b| {
+            b.iter(|| {
+                app.world
+                    .resource_scope(|world, mut snapshots: Mut<PhysicsSnapshots>| {
+                        let bytes = snapshots.load(start).unwrap();
+                        let context = bincode::deserialize(bytes).unwrap();
+                        let mut rapier = world.resource_mut::<RapierContext>();
+                        restore_rapier_context(&mut rapier, context);
+                    });
+                app.world.resource_mut::<CurrentFrame>().0 = start;
+
+                for _ in 0..MAX_PREDICTION {
+                    schedule.run(&mut app.world);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialize, deserialize, checksum, resimulate);
+criterion_main!(benches);
@@ -1,43 +1,245 @@
+use bevy::{ecs::system::EntityCommands, utils::HashMap};
+use bevy_rapier2d::rapier::prelude::{ColliderHandle, RigidBodyHandle};
+
 use crate::prelude::*;
 
+/// What makes a pool entity a bullet.  This is rolled back, so after a
+/// rollback it tells us which entities should be bullets again.
+#[derive(Component, Default, Reflect, Clone, Copy, Debug)]
+#[reflect(Component)]
+pub struct Projectile {
+    /// Simulation frames until it is handed back to the pool
+    pub frames_left: usize,
+    /// Who fired it, so they cannot shoot themselves
+    pub shooter: PlayerHandle,
+    /// What it was fired from, an index into [`Weapons`]
+    pub weapon: usize,
+}
+
 #[derive(Bundle)]
 pub struct BulletBundle {
+    projectile: Projectile,
     sprite: SpriteBundle,
     velocity: Velocity,
     rigid_body: RigidBody,
     collider: Collider,
     collision_groups: CollisionGroups,
-    rollback: Rollback,
+    solver_groups: SolverGroups,
+    active_events: ActiveEvents,
     name: Name,
+    smoothing: SmoothedVisual,
 }
 
 impl BulletBundle {
-    pub fn new(player_transform: &Transform, rollback: Rollback, texture: Handle<Image>) -> Self {
-        let p_rot = player_transform.rotation.to_euler(EulerRot::ZXY).0;
-
+    /// Fires a bullet from `position` along `angle`, which should come
+    /// straight from the inputs so every peer starts with the same bits
+    pub fn new(
+        shooter: PlayerHandle,
+        weapons: &Weapons,
+        weapon: usize,
+        position: Vec2,
+        angle: f32,
+        texture: Handle<Image>,
+    ) -> Self {
+        let definition = weapons.get(weapon);
+        let (half_length, half_width) = definition.projectile_size;
         Self {
-            name: Name::new(format!("Bullet")),
-            rollback,
+            name: Name::new("Bullet"),
+            projectile: Projectile {
+                frames_left: BULLET_LIFETIME_FRAMES,
+                shooter,
+                weapon,
+            },
             sprite: SpriteBundle {
                 texture,
                 transform: Transform {
-                    translation: Vec3::new(
-                        player_transform.translation.x,
-                        player_transform.translation.y,
-                        1.0,
-                    ),
-                    rotation: player_transform.rotation.clone(),
+                    translation: position.extend(1.0),
+                    rotation: rotation_z(angle),
                     ..default()
                 },
                 ..default()
             },
             rigid_body: RigidBody::Dynamic,
-            collider: Collider::cuboid(16.0, 1.0),
+            collider: Collider::cuboid(half_length, half_width),
             collision_groups: CollisionGroups::new(COL_BULLET, COL_FILTER_BULLET),
+            solver_groups: SolverGroups::new(COL_BULLET, COL_FILTER_BULLET_SOLVER),
+            // Hits come in through the collision log, see `expire_bullets`
+            active_events: ActiveEvents::COLLISION_EVENTS,
             velocity: Velocity {
-                linvel: Vec2::new(p_rot.cos(), p_rot.sin()) * 1000.0,
+                linvel: direction(angle) * definition.projectile_speed,
                 angvel: 0.0,
             },
+            smoothing: SmoothedVisual::default(),
+        }
+    }
+
+    /// Undoes [`BulletBundle::new`] when handing the entity back to the pool
+    pub fn strip(entity: &mut EntityCommands) {
+        entity.remove::<BulletBundle>();
+    }
+}
+
+/// Ages bullets, damages whoever they hit, and sends them back to the pool
+/// once they run out of time or hit something.  Hits are whatever a bullet
+/// started touching in the previous step, see [`GameCollision`].
+pub fn expire_bullets(
+    mut commands: Commands,
+    mut collisions: EventReader<GameCollision>,
+    weapons: Res<Weapons>,
+    mut bullets: Query<(Entity, &mut Projectile, &Transform, &Rollback), Without<ReturnToPool>>,
+    mut dudes: Query<(&Player, &mut LifeState, &mut Health, &Dash)>,
+    mut effects: EffectWriter,
+) {
+    // The collision log hands these over sorted by rollback id, so this is the
+    // same for every peer no matter what order Rapier reported them in.
+    // Damage only ever adds up, so that order does not matter either.
+    let mut hits = Vec::new();
+    for collision in collisions.iter().filter(|collision| collision.started) {
+        for (bullet, other) in [(collision.a, collision.b), (collision.b, collision.a)] {
+            let (shooter, weapon) = match bullets.get(bullet) {
+                Ok((_, projectile, ..)) => (projectile.shooter, projectile.weapon),
+                Err(_) => continue,
+            };
+
+            match dudes.get_mut(other) {
+                // Bullets start out inside whoever fired them
+                Ok((player, ..)) if player.handle == shooter => continue,
+                // Freshly respawned and dashing dudes soak up bullets unharmed
+                Ok((_, life, _, dash)) if !life.is_hittable() || dash.is_invulnerable() => {}
+                Ok((player, mut life, mut health, _)) => {
+                    health.hp = health.hp.saturating_sub(weapons.get(weapon).damage);
+                    life.hit_by |= 1 << shooter;
+                    log::trace!(
+                        "Bullet {:?} hit player {}, {} hp left",
+                        bullet,
+                        player.handle,
+                        health.hp
+                    );
+                }
+                Err(_) => {}
+            }
+            if !hits.contains(&bullet) {
+                hits.push(bullet);
+            }
         }
     }
+
+    for (entity, mut projectile, transform, rollback) in bullets.iter_mut() {
+        projectile.frames_left = projectile.frames_left.saturating_sub(1);
+
+        let hit = hits.contains(&entity);
+        if hit || projectile.frames_left == 0 {
+            log::trace!("Bullet {:?} done, hit: {}", entity, hit);
+            if hit {
+                effects.send(
+                    rollback,
+                    CosmeticEffect::Impact {
+                        position: transform.translation.truncate(),
+                    },
+                );
+            }
+            commands.entity(entity).insert(ReturnToPool {
+                strip: BulletBundle::strip,
+            });
+        }
+    }
+}
+
+/// bevy_ggrs puts back [`Projectile`], [`Transform`] and [`Velocity`] when it
+/// rolls back, and we put back the physics state, but everything else on a
+/// bullet is ours to fix.  Bullets that were handed back to the pool after the
+/// frame we loaded get their components back and are reattached to the bodies
+/// Rapier restored for them.  Bullets that were fired after that frame lose
+/// theirs again.
+#[allow(clippy::type_complexity)]
+pub fn restore_bullets(
+    mut commands: Commands,
+    rollback_status: Res<RollbackStatus>,
+    rapier: Res<RapierContext>,
+    weapons: Res<Weapons>,
+    asset_server: Res<AssetServer>,
+    bullets: Query<(
+        Entity,
+        &Projectile,
+        &Transform,
+        &GlobalTransform,
+        &Velocity,
+        Option<&RapierRigidBodyHandle>,
+        Option<&RapierColliderHandle>,
+    )>,
+    leftovers: Query<
+        Entity,
+        (
+            With<DeterministicSpawn>,
+            With<Collider>,
+            Without<Projectile>,
+        ),
+    >,
+) {
+    if !rollback_status.is_rollback {
+        return;
+    }
+
+    for entity in leftovers.iter() {
+        log::trace!("Bullet {:?} was never fired", entity);
+        commands
+            .entity(entity)
+            .remove::<BulletBundle>()
+            .remove::<(RapierRigidBodyHandle, RapierColliderHandle)>();
+    }
+
+    // bevy_rapier tags everything it creates with the entity it belongs to
+    let bodies: HashMap<u128, RigidBodyHandle> = rapier
+        .bodies
+        .iter()
+        .map(|(handle, body)| (body.user_data, handle))
+        .collect();
+    let colliders: HashMap<u128, ColliderHandle> = rapier
+        .colliders
+        .iter()
+        .map(|(handle, collider)| (collider.user_data, handle))
+        .collect();
+
+    for (entity, projectile, transform, global_transform, velocity, body_handle, collider_handle) in
+        bullets.iter()
+    {
+        let key = entity.to_bits() as u128;
+        let (body, collider) = match (bodies.get(&key), colliders.get(&key)) {
+            (Some(&body), Some(&collider)) => (body, collider),
+            _ => {
+                // Every bullet in a snapshot was already stepped once, so this
+                // should not happen.  Let bevy_rapier create it from scratch.
+                log::warn!("Bullet {:?} is missing from the physics world", entity);
+                commands
+                    .entity(entity)
+                    .remove::<(RapierRigidBodyHandle, RapierColliderHandle)>();
+                continue;
+            }
+        };
+
+        if body_handle.map(|h| h.0) == Some(body) && collider_handle.map(|h| h.0) == Some(collider)
+        {
+            continue;
+        }
+
+        log::trace!("Bullet {:?} is back", entity);
+        // Only the parts of the bundle bevy_ggrs does not restore matter, so
+        // put everything it did restore back on top of it
+        let bundle = BulletBundle::new(
+            projectile.shooter,
+            &weapons,
+            projectile.weapon,
+            Vec2::ZERO,
+            0.0,
+            asset_server.load(weapons.get(projectile.weapon).texture.as_str()),
+        );
+        commands.entity(entity).insert(bundle).insert((
+            *projectile,
+            *transform,
+            *global_transform,
+            *velocity,
+            RapierRigidBodyHandle(body),
+            RapierColliderHandle(collider),
+        ));
+    }
 }
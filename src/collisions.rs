@@ -0,0 +1,177 @@
+use bevy_rapier2d::rapier::prelude::CollisionEventFlags;
+
+use crate::prelude::*;
+
+/// Two colliders started or stopped touching during the previous physics
+/// step.  `a` always has the lower rollback id of the two.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GameCollision {
+    pub a: Entity,
+    pub b: Entity,
+    pub started: bool,
+    pub sensor: bool,
+}
+
+impl GameCollision {
+    /// The other entity, if `entity` is part of this collision
+    pub fn other(&self, entity: Entity) -> Option<Entity> {
+        if self.a == entity {
+            Some(self.b)
+        } else if self.b == entity {
+            Some(self.a)
+        } else {
+            None
+        }
+    }
+}
+
+/// Two colliders pushed on each other during the previous physics step.  `a`
+/// always has the lower rollback id of the two.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GameContactForce {
+    pub a: Entity,
+    pub b: Entity,
+    pub total_force_magnitude: f32,
+    pub max_force_magnitude: f32,
+}
+
+/// Everything Rapier reported for one step, already in a deterministic order
+#[derive(Debug)]
+struct StepEvents {
+    frame: Frame,
+    collisions: Vec<GameCollision>,
+    contact_forces: Vec<GameContactForce>,
+}
+
+impl Default for StepEvents {
+    fn default() -> Self {
+        Self {
+            frame: ggrs::NULL_FRAME,
+            collisions: Vec::new(),
+            contact_forces: Vec::new(),
+        }
+    }
+}
+
+/// Frame-indexed ring of the events each physics step produced, laid out just
+/// like [`PhysicsSnapshots`].  Rapier only reports events while stepping, so
+/// after a rollback the events that led to the state we loaded are long gone
+/// from its queues.  Keeping them per frame means the first frame we
+/// resimulate sees exactly what it saw the first time around.
+#[derive(Resource)]
+pub struct CollisionLog {
+    slots: [StepEvents; PHYSICS_SNAPSHOT_SLOTS],
+}
+
+impl Default for CollisionLog {
+    fn default() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| StepEvents::default()),
+        }
+    }
+}
+
+impl CollisionLog {
+    fn slot(frame: Frame) -> usize {
+        frame.rem_euclid(PHYSICS_SNAPSHOT_SLOTS as Frame) as usize
+    }
+
+    fn get(&self, frame: Frame) -> Option<&StepEvents> {
+        let slot = &self.slots[Self::slot(frame)];
+        (slot.frame == frame).then_some(slot)
+    }
+}
+
+/// Sort key for an entity in a physics event.  Everything that takes part in
+/// physics comes out of the spawn pool and so has a rollback id set aside for
+/// it, even the walls, which never carry theirs.
+fn rollback_key(pool: &SpawnPool, entity: Entity) -> u32 {
+    match pool.rollback_id(entity) {
+        Some(id) => id,
+        None => {
+            log::warn!("{:?} collided without a rollback id", entity);
+            u32::MAX
+        }
+    }
+}
+
+fn ordered(pool: &SpawnPool, a: Entity, b: Entity) -> (Entity, Entity, u32, u32) {
+    let (key_a, key_b) = (rollback_key(pool, a), rollback_key(pool, b));
+    if key_a <= key_b {
+        (a, b, key_a, key_b)
+    } else {
+        (b, a, key_b, key_a)
+    }
+}
+
+/// Takes whatever Rapier reported for the step we just took, sorts it by
+/// rollback id and files it under the current frame.  Runs after the step.
+pub fn record_collision_events(
+    current_frame: Res<CurrentFrame>,
+    mut collision_log: ResMut<CollisionLog>,
+    mut collision_events: ResMut<Events<CollisionEvent>>,
+    mut contact_force_events: ResMut<Events<ContactForceEvent>>,
+    pool: Res<SpawnPool>,
+) {
+    let step = &mut collision_log.slots[CollisionLog::slot(current_frame.0)];
+    step.frame = current_frame.0;
+
+    let mut collisions: Vec<_> = collision_events
+        .drain()
+        .map(|event| {
+            let (a, b, flags, started) = match event {
+                CollisionEvent::Started(a, b, flags) => (a, b, flags, true),
+                CollisionEvent::Stopped(a, b, flags) => (a, b, flags, false),
+            };
+            let (a, b, key_a, key_b) = ordered(&pool, a, b);
+            let collision = GameCollision {
+                a,
+                b,
+                started,
+                sensor: flags.contains(CollisionEventFlags::SENSOR),
+            };
+            ((key_a, key_b, started), collision)
+        })
+        .collect();
+    collisions.sort_by_key(|(key, _)| *key);
+    step.collisions.clear();
+    step.collisions
+        .extend(collisions.into_iter().map(|(_, collision)| collision));
+
+    let mut contact_forces: Vec<_> = contact_force_events
+        .drain()
+        .map(|event| {
+            let (a, b, key_a, key_b) = ordered(&pool, event.collider1, event.collider2);
+            let force = GameContactForce {
+                a,
+                b,
+                total_force_magnitude: event.total_force_magnitude,
+                max_force_magnitude: event.max_force_magnitude,
+            };
+            ((key_a, key_b), force)
+        })
+        .collect();
+    contact_forces.sort_by_key(|(key, _)| *key);
+    step.contact_forces.clear();
+    step.contact_forces
+        .extend(contact_forces.into_iter().map(|(_, force)| force));
+}
+
+/// Hands the events from the previous step to the game logic.  Anything in
+/// [`GAME_SYSTEMS`] can read them with an `EventReader` as long as it runs
+/// after this.  Nothing is left over from frames we rolled back from, since the
+/// queues are emptied every frame.
+pub fn dispatch_collision_events(
+    current_frame: Res<CurrentFrame>,
+    collision_log: Res<CollisionLog>,
+    mut collisions: ResMut<Events<GameCollision>>,
+    mut contact_forces: ResMut<Events<GameContactForce>>,
+) {
+    collisions.clear();
+    contact_forces.clear();
+
+    if let Some(step) = collision_log.get(current_frame.0 - 1) {
+        collisions.extend(step.collisions.iter().copied());
+        contact_forces.extend(step.contact_forces.iter().copied());
+    }
+}
@@ -4,9 +4,26 @@ pub const COL_DUDE: Group = Group::GROUP_1;
 pub const COL_BULLET: Group = Group::GROUP_2;
 pub const COL_TERRAIN: Group = Group::GROUP_3;
 
+// Bullets fly through each other, the pellets of a shot all start in the same
+// spot
 pub const COL_FILTER_BULLET: Group =
+    Group::from_bits_truncate(COL_TERRAIN.bits() | COL_DUDE.bits());
+pub const COL_FILTER_DUDE: Group =
     Group::from_bits_truncate(COL_TERRAIN.bits() | COL_BULLET.bits());
-pub const COL_FILTER_DUDE: Group = COL_TERRAIN;
+// Bullets only register hits on dudes, they do not push them around
+pub const COL_FILTER_BULLET_SOLVER: Group = COL_TERRAIN;
+// Dudes walk through bullets
+pub const COL_FILTER_DUDE_MOVEMENT: Group = COL_TERRAIN;
 
 pub const TILE_SIZE: usize = 64;
 pub const PLAYER_MOVE_SPEED: f32 = 5.0;
+pub const BULLET_LIFETIME_FRAMES: usize = 120;
+pub const RESPAWN_FRAMES: usize = 180;
+pub const SPAWN_PROTECTION_FRAMES: usize = 120;
+pub const DASH_SPEED: f32 = 20.0;
+pub const DASH_FRAMES: usize = 8;
+pub const DASH_COOLDOWN_FRAMES: usize = 90;
+// A little longer than the dash itself, so a dash through a bullet is safe
+pub const DASH_INVULNERABLE_FRAMES: usize = 12;
+pub const FRAG_LIMIT: usize = 10;
+pub const MATCH_SECONDS: usize = 180;
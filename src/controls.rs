@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+
+use bevy::ecs::system::SystemParam;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// Where the bindings live between runs, next to wherever the game was
+/// started from
+#[cfg(not(target_arch = "wasm32"))]
+const CONTROLS_PATH: &str = "controls.ron";
+
+/// Everything a binding can do.  The sticks are not in here, they always
+/// move and aim.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    Primary,
+    Secondary,
+    Reload,
+    NextWeapon,
+}
+
+impl Action {
+    pub const ALL: [Action; 8] = [
+        Action::Up,
+        Action::Down,
+        Action::Left,
+        Action::Right,
+        Action::Primary,
+        Action::Secondary,
+        Action::Reload,
+        Action::NextWeapon,
+    ];
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    Gamepad(GamepadButtonType),
+}
+
+impl Binding {
+    /// Whether `other` comes from the same kind of device, a new binding only
+    /// replaces those
+    fn same_device(&self, other: &Binding) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// Which buttons do what.  Keys and the mouse drive our first local player,
+/// gamepad buttons whoever the gamepad is assigned to.
+#[derive(Clone, Debug, PartialEq, Resource, Serialize, Deserialize)]
+pub struct Controls(BTreeMap<Action, Vec<Binding>>);
+
+impl Default for Controls {
+    fn default() -> Self {
+        use Binding::*;
+        Self(BTreeMap::from([
+            (Action::Up, vec![Key(KeyCode::W)]),
+            (Action::Down, vec![Key(KeyCode::S)]),
+            (Action::Left, vec![Key(KeyCode::A)]),
+            (Action::Right, vec![Key(KeyCode::D)]),
+            (
+                Action::Primary,
+                vec![
+                    Mouse(MouseButton::Left),
+                    Gamepad(GamepadButtonType::RightTrigger2),
+                ],
+            ),
+            (
+                Action::Secondary,
+                vec![
+                    Mouse(MouseButton::Right),
+                    Gamepad(GamepadButtonType::LeftTrigger2),
+                ],
+            ),
+            (
+                Action::Reload,
+                vec![Key(KeyCode::R), Gamepad(GamepadButtonType::West)],
+            ),
+            (
+                Action::NextWeapon,
+                vec![Key(KeyCode::Q), Gamepad(GamepadButtonType::North)],
+            ),
+        ]))
+    }
+}
+
+impl Controls {
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.0.get(&action).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Binds `binding` to `action`, in place of whatever the same device had
+    /// bound to it before
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.0.entry(action).or_default();
+        bindings.retain(|b| !b.same_device(&binding));
+        bindings.push(binding);
+    }
+
+    /// The saved bindings, or the defaults if there are none we can read
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        match std::fs::read_to_string(CONTROLS_PATH) {
+            Ok(source) => match ron::from_str(&source) {
+                Ok(controls) => return controls,
+                Err(e) => warn!("Ignoring {}: {}", CONTROLS_PATH, e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not read {}: {}", CONTROLS_PATH, e),
+        }
+        Self::default()
+    }
+
+    pub fn save(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let result = ron::ser::to_string_pretty(self, default())
+                .map_err(|e| e.to_string())
+                .and_then(|source| {
+                    std::fs::write(CONTROLS_PATH, source).map_err(|e| e.to_string())
+                });
+            if let Err(e) = result {
+                error!("Could not save {}: {}", CONTROLS_PATH, e);
+            }
+        }
+    }
+}
+
+/// Reads actions for a local player through the [`Controls`]
+#[derive(SystemParam)]
+pub struct ActionInput<'w, 's> {
+    controls: Res<'w, Controls>,
+    local_handles: Res<'w, LocalHandles>,
+    gamepads: Res<'w, GamepadAssignments>,
+    keys: Res<'w, Input<KeyCode>>,
+    mouse: Res<'w, Input<MouseButton>>,
+    buttons: Res<'w, Input<GamepadButton>>,
+    #[system_param(ignore)]
+    _marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> ActionInput<'w, 's> {
+    pub fn pressed(&self, handle: PlayerHandle, action: Action) -> bool {
+        self.check(handle, action, false)
+    }
+
+    pub fn just_pressed(&self, handle: PlayerHandle, action: Action) -> bool {
+        self.check(handle, action, true)
+    }
+
+    fn check(&self, handle: PlayerHandle, action: Action, just: bool) -> bool {
+        fn is_down<T>(input: &Input<T>, button: T, just: bool) -> bool
+        where
+            T: Copy + Eq + std::hash::Hash + Send + Sync + 'static,
+        {
+            if just {
+                input.just_pressed(button)
+            } else {
+                input.pressed(button)
+            }
+        }
+
+        let keyboard = self.local_handles.handles.first() == Some(&handle);
+        let gamepad = self.gamepads.get(handle);
+        self.controls
+            .bindings(action)
+            .iter()
+            .any(|binding| match *binding {
+                Binding::Key(key) => keyboard && is_down(&self.keys, key, just),
+                Binding::Mouse(button) => keyboard && is_down(&self.mouse, button, just),
+                Binding::Gamepad(button_type) => gamepad.is_some_and(|gamepad| {
+                    is_down(
+                        &self.buttons,
+                        GamepadButton::new(gamepad, button_type),
+                        just,
+                    )
+                }),
+            })
+    }
+}
+
+/// The rebinding screen, F1 toggles it
+#[derive(Default, Resource)]
+pub struct ControlsScreen {
+    pub open: bool,
+    /// The action waiting for its next button
+    rebinding: Option<Action>,
+}
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Controls::load())
+            .insert_resource(ControlsScreen::default())
+            .add_system(toggle_controls_screen)
+            .add_system(controls_screen)
+            .add_system(capture_binding.after(controls_screen));
+    }
+}
+
+pub fn toggle_controls_screen(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut screen: ResMut<ControlsScreen>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F1) {
+        screen.open = !screen.open;
+        screen.rebinding = None;
+    }
+}
+
+pub fn controls_screen(
+    mut egui_context: ResMut<EguiContext>,
+    mut screen: ResMut<ControlsScreen>,
+    mut controls: ResMut<Controls>,
+) {
+    if !screen.open {
+        return;
+    }
+
+    let mut open = true;
+    egui::Window::new("Controls")
+        .open(&mut open)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            egui::Grid::new("bindings").show(ui, |ui| {
+                for action in Action::ALL {
+                    ui.label(format!("{:?}", action));
+                    let bindings = controls
+                        .bindings(action)
+                        .iter()
+                        .map(|binding| match binding {
+                            Binding::Key(key) => format!("{:?}", key),
+                            Binding::Mouse(button) => format!("Mouse {:?}", button),
+                            Binding::Gamepad(button) => format!("Pad {:?}", button),
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    ui.label(bindings);
+
+                    if screen.rebinding == Some(action) {
+                        ui.label("Press something...");
+                    } else if ui.button("Rebind").clicked() {
+                        screen.rebinding = Some(action);
+                    }
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+            if ui.button("Reset to defaults").clicked() {
+                *controls = Controls::default();
+                controls.save();
+            }
+        });
+
+    if !open {
+        screen.open = false;
+        screen.rebinding = None;
+    }
+}
+
+/// Binds whatever is pressed next to the action waiting for it, and saves
+pub fn capture_binding(
+    mut screen: ResMut<ControlsScreen>,
+    mut controls: ResMut<Controls>,
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    buttons: Res<Input<GamepadButton>>,
+) {
+    let action = match screen.rebinding {
+        Some(action) => action,
+        None => return,
+    };
+
+    let binding = keys
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or_else(|| {
+            buttons
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Gamepad(button.button_type))
+        });
+
+    if let Some(binding) = binding {
+        info!("Bound {:?} to {:?}", binding, action);
+        controls.rebind(action, binding);
+        controls.save();
+        screen.rebinding = None;
+    }
+}
+
+#[test]
+fn test_controls_round_trip() {
+    let mut controls = Controls::default();
+    controls.rebind(Action::Primary, Binding::Key(KeyCode::Space));
+    controls.rebind(Action::Primary, Binding::Mouse(MouseButton::Middle));
+
+    // Each device keeps one binding per action
+    assert_eq!(
+        controls.bindings(Action::Primary),
+        &[
+            Binding::Gamepad(GamepadButtonType::RightTrigger2),
+            Binding::Key(KeyCode::Space),
+            Binding::Mouse(MouseButton::Middle),
+        ]
+    );
+
+    let source = ron::ser::to_string_pretty(&controls, default()).unwrap();
+    assert_eq!(ron::from_str::<Controls>(&source).unwrap(), controls);
+}
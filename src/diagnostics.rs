@@ -0,0 +1,173 @@
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    utils::{Duration, Instant},
+};
+use bevy_egui::{egui, EguiContext};
+
+use crate::prelude::*;
+
+/// Feeds what happens inside the rollback schedule into Bevy's [`Diagnostics`]
+/// so network-induced hitching shows up next to the frame time.
+#[derive(Default)]
+pub struct RollbackDiagnosticsPlugin {
+    /// Show the overlay from the start, it can always be toggled with F3
+    pub overlay: bool,
+}
+
+impl Plugin for RollbackDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RollbackCounters::default())
+            .insert_resource(RollbackDepthHistogram::default())
+            .insert_resource(RollbackOverlay(self.overlay))
+            .add_startup_system(setup_rollback_diagnostics)
+            .add_system(update_rollback_diagnostics)
+            .add_system(toggle_rollback_overlay)
+            .add_system(rollback_overlay.after(update_rollback_diagnostics));
+    }
+}
+
+pub const ROLLBACKS_PER_SECOND: DiagnosticId =
+    DiagnosticId::from_u128(239810283771024418326520944861709215873);
+pub const ROLLBACK_DEPTH: DiagnosticId =
+    DiagnosticId::from_u128(93862103975119036224866416722064981031);
+pub const RESIMULATED_FRAMES: DiagnosticId =
+    DiagnosticId::from_u128(170315539716290186358290117451238406637);
+pub const ROLLBACK_SCHEDULE_TIME: DiagnosticId =
+    DiagnosticId::from_u128(52148792716870962035781232937302188512);
+
+/// Collected from inside the rollback schedule, and drained into
+/// [`Diagnostics`] once per render frame.  Not rolled back, obviously.
+#[derive(Default, Resource)]
+pub struct RollbackCounters {
+    pub rollbacks: usize,
+    pub rollback_depth: usize,
+    pub resimulated_frames: usize,
+    pub schedule_time: Duration,
+    schedule_start: Option<Instant>,
+}
+
+/// How many rollbacks went back how far, over the whole session.  Index is
+/// the number of frames resimulated, the last bucket also counts anything
+/// deeper than our prediction window.
+#[derive(Default, Resource)]
+pub struct RollbackDepthHistogram(pub [usize; MAX_PREDICTION + 2]);
+
+#[derive(Default, Resource)]
+pub struct RollbackOverlay(pub bool);
+
+pub fn setup_rollback_diagnostics(mut diagnostics: ResMut<Diagnostics>) {
+    diagnostics.add(Diagnostic::new(
+        ROLLBACKS_PER_SECOND,
+        "rollbacks_per_second",
+        60,
+    ));
+    diagnostics.add(Diagnostic::new(ROLLBACK_DEPTH, "rollback_depth", 60));
+    diagnostics.add(Diagnostic::new(
+        RESIMULATED_FRAMES,
+        "resimulated_frames",
+        60,
+    ));
+    diagnostics.add(Diagnostic::new(ROLLBACK_SCHEDULE_TIME, "rollback_time", 60).with_suffix("ms"));
+}
+
+/// Must run first in the rollback schedule
+pub fn start_rollback_timer(mut counters: ResMut<RollbackCounters>) {
+    counters.schedule_start = Some(Instant::now());
+}
+
+/// Must run last in the rollback schedule
+pub fn stop_rollback_timer(mut counters: ResMut<RollbackCounters>) {
+    if let Some(start) = counters.schedule_start.take() {
+        counters.schedule_time += start.elapsed();
+    }
+}
+
+pub fn count_rollbacks(
+    rollback_status: Res<RollbackStatus>,
+    mut counters: ResMut<RollbackCounters>,
+    mut histogram: ResMut<RollbackDepthHistogram>,
+) {
+    if rollback_status.is_rollback {
+        let depth = rollback_status.rollback_depth.max(0) as usize;
+        counters.rollbacks += 1;
+        counters.rollback_depth += depth;
+
+        let bucket = depth.min(histogram.0.len() - 1);
+        histogram.0[bucket] += 1;
+    }
+
+    if rollback_status.is_replay {
+        counters.resimulated_frames += 1;
+    }
+}
+
+pub fn update_rollback_diagnostics(
+    mut diagnostics: ResMut<Diagnostics>,
+    mut counters: ResMut<RollbackCounters>,
+    time: Res<Time>,
+) {
+    let delta_seconds = time.raw_delta_seconds_f64();
+    if delta_seconds == 0.0 {
+        return;
+    }
+
+    let counters = std::mem::take(&mut *counters);
+    diagnostics.add_measurement(ROLLBACKS_PER_SECOND, || {
+        counters.rollbacks as f64 / delta_seconds
+    });
+    if counters.rollbacks > 0 {
+        diagnostics.add_measurement(ROLLBACK_DEPTH, || {
+            counters.rollback_depth as f64 / counters.rollbacks as f64
+        });
+    }
+    diagnostics.add_measurement(RESIMULATED_FRAMES, || counters.resimulated_frames as f64);
+    diagnostics.add_measurement(ROLLBACK_SCHEDULE_TIME, || {
+        counters.schedule_time.as_secs_f64() * 1000.0
+    });
+}
+
+pub fn toggle_rollback_overlay(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut overlay: ResMut<RollbackOverlay>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        overlay.0 = !overlay.0;
+    }
+}
+
+pub fn rollback_overlay(
+    mut egui_context: ResMut<EguiContext>,
+    overlay: Res<RollbackOverlay>,
+    diagnostics: Res<Diagnostics>,
+    histogram: Res<RollbackDepthHistogram>,
+) {
+    if !overlay.0 {
+        return;
+    }
+
+    egui::Window::new("Rollback")
+        .anchor(egui::Align2::RIGHT_TOP, [-10.0, 10.0])
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            for (id, label) in [
+                (ROLLBACKS_PER_SECOND, "Rollbacks/s"),
+                (ROLLBACK_DEPTH, "Avg. depth"),
+                (RESIMULATED_FRAMES, "Resimulated/frame"),
+                (ROLLBACK_SCHEDULE_TIME, "Schedule ms/frame"),
+            ] {
+                let value = diagnostics
+                    .get(id)
+                    .and_then(|d| d.smoothed())
+                    .unwrap_or_default();
+                ui.label(format!("{}: {:.2}", label, value));
+            }
+
+            ui.separator();
+            ui.label("Depth histogram");
+            let most = histogram.0.iter().copied().max().unwrap_or_default().max(1);
+            for (depth, count) in histogram.0.iter().enumerate().skip(1) {
+                let bar = "#".repeat(count * 20 / most);
+                ui.monospace(format!("{:>2} {:<20} {}", depth, bar, count));
+            }
+        });
+}
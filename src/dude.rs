@@ -7,24 +7,27 @@ pub struct DudeBundle {
     rigid_body: RigidBody,
     collider: Collider,
     health: Health,
+    life: LifeState,
+    dash: Dash,
+    weapon: Weapon,
     collision_groups: CollisionGroups,
+    active_events: ActiveEvents,
     player: Player,
-    rollback: Rollback,
     name: Name,
     controller: KinematicCharacterController,
+    smoothing: SmoothedVisual,
 }
 
 impl DudeBundle {
     pub fn new(
         player: usize,
-        rollback: Rollback,
         texture: Handle<Image>,
         spawn_point: Vec2,
         max_hp: usize,
+        weapon: Weapon,
     ) -> Self {
         Self {
             name: Name::new(format!("Player {}", player)),
-            rollback,
             sprite: SpriteBundle {
                 texture,
                 transform: Transform {
@@ -36,13 +39,18 @@ impl DudeBundle {
             rigid_body: RigidBody::KinematicPositionBased,
             collider: Collider::ball(16.0),
             collision_groups: CollisionGroups::new(COL_DUDE, COL_FILTER_DUDE),
+            active_events: ActiveEvents::COLLISION_EVENTS,
             health: Health::new(max_hp),
+            life: LifeState::default(),
+            dash: Dash::default(),
+            weapon,
             player: Player { handle: player },
             controller: KinematicCharacterController {
-                filter_groups: Some(CollisionGroups::new(COL_DUDE, COL_FILTER_DUDE)),
+                filter_groups: Some(CollisionGroups::new(COL_DUDE, COL_FILTER_DUDE_MOVEMENT)),
                 slide: true,
                 ..default()
             },
+            smoothing: SmoothedVisual::default(),
         }
     }
 }
@@ -78,3 +86,35 @@ pub fn dude_hp_bar(
         component_observer(|h: &Health| h.hp as f32 / h.max as f32),
     )
 }
+
+pub fn dude_dash_bar(
+    entity: Entity,
+) -> (
+    StatBarColor,
+    StatBarEmptyColor,
+    StatBarBorder,
+    StatBarValue,
+    StatBarSize,
+    StatBarSubject,
+    StatBarPosition,
+    StatBarZDepth,
+    StatBarObserver,
+) {
+    (
+        StatBarColor(Color::CYAN),
+        StatBarEmptyColor(Color::BLACK),
+        StatBarBorder {
+            color: Color::DARK_GRAY,
+            thickness: 2.0,
+        },
+        StatBarValue(1.0),
+        StatBarSize {
+            full_length: 50.0,
+            thickness: 3.0,
+        },
+        StatBarSubject(entity),
+        StatBarPosition(32.0 * Vec2::Y),
+        StatBarZDepth(2.0),
+        component_observer(|d: &Dash| d.charge()),
+    )
+}
@@ -14,12 +14,20 @@ pub struct Tile {
     pub kind: TileKind,
 }
 
+/// Generated the same by every peer from the same seed, and never changes
+/// after that, so it does not need to be rolled back
+#[derive(Resource)]
 pub struct Dungeon {
     tiles: Vec<Vec<Tile>>,
     spawn_points: Vec<(usize, usize)>,
-    spawn_index: usize,
 }
 
+/// Which spawn point is up next.  Rolled back, since respawns happen in the
+/// middle of the game.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Resource, Hash, Reflect)]
+#[reflect(Hash, Resource, PartialEq)]
+pub struct SpawnPointIndex(pub usize);
+
 impl Dungeon {
     pub fn gen(seed: u64) -> Dungeon {
         let mut rng = SmallRng::seed_from_u64(seed);
@@ -111,15 +119,17 @@ impl Dungeon {
         Dungeon {
             tiles,
             spawn_points,
-            spawn_index: 0,
         }
     }
-    pub fn get_spawn_point(&mut self) -> (usize, usize) {
-        let point = self.spawn_points[self.spawn_index];
-        self.spawn_index += 1;
-        self.spawn_index %= self.spawn_points.len();
+    pub fn get_spawn_point(&self, spawn_index: &mut SpawnPointIndex) -> (usize, usize) {
+        let point = self.spawn_points[spawn_index.0 % self.spawn_points.len()];
+        spawn_index.0 = (spawn_index.0 + 1) % self.spawn_points.len();
         point
     }
+    /// Where a spawn point is in the world
+    pub fn spawn_position(point: (usize, usize)) -> Vec2 {
+        Vec2::new((point.0 * TILE_SIZE) as f32, (point.1 * TILE_SIZE) as f32)
+    }
     pub fn get_tiles(&self) -> Vec<Vec<Tile>> {
         self.tiles.clone()
     }
@@ -171,6 +181,6 @@ impl Room {
                 }
             }
         }
-        return false;
+        false
     }
 }
@@ -0,0 +1,275 @@
+use std::mem::{discriminant, Discriminant};
+
+use bevy::{ecs::system::SystemParam, utils::HashMap};
+
+use crate::{camera::pin_camera_to_player_system, prelude::*};
+
+/// How far back a rollback can still reach, even in a sync test session where
+/// the confirmed frame is always the current one
+const EFFECT_HISTORY_FRAMES: Frame = MAX_PREDICTION as Frame + 1;
+
+/// How much screen shake trauma wears off per second
+const SCREEN_SHAKE_DECAY: f32 = 2.0;
+/// Camera offset at full trauma, in pixels
+const SCREEN_SHAKE_MAX_OFFSET: f32 = 12.0;
+
+/// Something purely for show.  Nothing in the simulation may depend on these,
+/// they are only ever played on the render side.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CosmeticEffect {
+    MuzzleFlash { position: Vec2, rotation: Quat },
+    Impact { position: Vec2 },
+    ScreenShake { trauma: f32 },
+}
+
+/// Identifies an effect across resimulations.  The same source pushing the
+/// same kind of effect on the same frame is the same effect, whichever pass
+/// over that frame it came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EffectKey {
+    pub frame: Frame,
+    pub source: u32,
+    kind: Discriminant<CosmeticEffect>,
+}
+
+/// Effects pushed from the rollback schedule, waiting to be played once it is
+/// done for this render frame.  Not rolled back: it is what remembers which
+/// effects were already played so resimulations do not play them again.
+#[derive(Default, Resource)]
+pub struct CosmeticEffects {
+    pending: Vec<(EffectKey, CosmeticEffect)>,
+    /// Everything played recently enough to be rolled back, with whatever we
+    /// spawned for it
+    played: HashMap<EffectKey, Option<Entity>>,
+    /// Played effects from frames we rolled back to, which have not come up
+    /// again in the resimulation yet
+    unconfirmed: Vec<EffectKey>,
+}
+
+impl CosmeticEffects {
+    pub fn push(&mut self, key: EffectKey, effect: CosmeticEffect) {
+        if self.played.contains_key(&key) {
+            // Already on screen, and it still happened
+            self.unconfirmed.retain(|k| *k != key);
+        } else if !self.pending.iter().any(|(k, _)| *k == key) {
+            self.pending.push((key, effect));
+        }
+    }
+
+    /// Forgets everything pushed from `frame` onwards, the resimulation will
+    /// push it again if it still happens
+    pub fn rewind(&mut self, frame: Frame) {
+        self.pending.retain(|(key, _)| key.frame < frame);
+        for key in self.played.keys() {
+            if key.frame >= frame && !self.unconfirmed.contains(key) {
+                self.unconfirmed.push(*key);
+            }
+        }
+    }
+}
+
+/// Lets rollback systems queue up cosmetic effects for the frame they are
+/// simulating
+#[derive(SystemParam)]
+pub struct EffectWriter<'w, 's> {
+    current_frame: Res<'w, CurrentFrame>,
+    effects: ResMut<'w, CosmeticEffects>,
+    #[system_param(ignore)]
+    _marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> EffectWriter<'w, 's> {
+    pub fn send(&mut self, source: &Rollback, effect: CosmeticEffect) {
+        let key = EffectKey {
+            frame: self.current_frame.0,
+            source: source.id(),
+            kind: discriminant(&effect),
+        };
+        self.effects.push(key, effect);
+    }
+}
+
+/// Sprites we spawn for effects clean themselves up after this
+#[derive(Component)]
+pub struct EffectLifetime(pub Timer);
+
+#[derive(Default, Resource)]
+pub struct ScreenShake {
+    pub trauma: f32,
+    offset: Vec2,
+}
+
+pub struct CosmeticEffectsPlugin;
+
+impl Plugin for CosmeticEffectsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(CosmeticEffects::default())
+            .insert_resource(ScreenShake::default())
+            .add_system(play_cosmetic_effects)
+            .add_system(fade_effects)
+            .add_system(
+                shake_camera
+                    .after(play_cosmetic_effects)
+                    .after(pin_camera_to_player_system),
+            );
+    }
+}
+
+/// Runs in the rollback schedule, right after we know whether we rolled back
+pub fn rewind_cosmetic_effects(
+    rollback_status: Res<RollbackStatus>,
+    mut effects: ResMut<CosmeticEffects>,
+) {
+    if rollback_status.is_rollback {
+        effects.rewind(rollback_status.rollback_frame);
+    }
+}
+
+/// Runs once per render frame after the rollback schedule has caught up.
+/// Takes back anything the resimulations did not repeat and plays whatever is
+/// new.
+pub fn play_cosmetic_effects(
+    mut commands: Commands,
+    mut effects: ResMut<CosmeticEffects>,
+    mut screen_shake: ResMut<ScreenShake>,
+    confirmed_frame: Res<ConfirmedFrame>,
+    asset_server: Res<AssetServer>,
+) {
+    let effects = &mut *effects;
+
+    for key in effects.unconfirmed.drain(..) {
+        log::trace!("Cancelling effect {:?}", key);
+        if let Some(entity) = effects.played.remove(&key).flatten() {
+            if let Some(entity) = commands.get_entity(entity) {
+                entity.despawn_recursive();
+            }
+        }
+    }
+
+    for (key, effect) in effects.pending.drain(..) {
+        log::trace!("Playing effect {:?}: {:?}", key, effect);
+        let entity = match effect {
+            CosmeticEffect::MuzzleFlash { position, rotation } => Some(
+                commands
+                    .spawn(SpriteBundle {
+                        texture: asset_server.load("bullet.png"),
+                        sprite: Sprite {
+                            color: Color::YELLOW,
+                            ..default()
+                        },
+                        transform: Transform::from_translation(position.extend(2.0))
+                            .with_rotation(rotation)
+                            .with_scale(Vec3::new(0.75, 4.0, 1.0)),
+                        ..default()
+                    })
+                    .insert(EffectLifetime(Timer::from_seconds(0.05, TimerMode::Once)))
+                    .id(),
+            ),
+            CosmeticEffect::Impact { position } => Some(
+                commands
+                    .spawn(SpriteBundle {
+                        sprite: Sprite {
+                            color: Color::ORANGE_RED,
+                            custom_size: Some(Vec2::splat(12.0)),
+                            ..default()
+                        },
+                        transform: Transform::from_translation(position.extend(2.0)),
+                        ..default()
+                    })
+                    .insert(EffectLifetime(Timer::from_seconds(0.2, TimerMode::Once)))
+                    .id(),
+            ),
+            CosmeticEffect::ScreenShake { trauma } => {
+                // Shaking is over too quickly to be worth taking back
+                screen_shake.trauma = (screen_shake.trauma + trauma).min(1.0);
+                None
+            }
+        };
+        effects.played.insert(key, entity);
+    }
+
+    // Nothing can roll back this far anymore
+    let oldest = confirmed_frame.0 - EFFECT_HISTORY_FRAMES;
+    effects.played.retain(|key, _| key.frame >= oldest);
+}
+
+pub fn fade_effects(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut EffectLifetime, &mut Sprite)>,
+    time: Res<Time>,
+) {
+    for (entity, mut lifetime, mut sprite) in query.iter_mut() {
+        lifetime.0.tick(time.delta());
+        if lifetime.0.finished() {
+            commands.entity(entity).despawn_recursive();
+        } else {
+            sprite.color.set_a(lifetime.0.percent_left());
+        }
+    }
+}
+
+/// Jiggles the camera around wherever it was pinned this frame
+pub fn shake_camera(
+    mut screen_shake: ResMut<ScreenShake>,
+    mut camera: Query<&mut Transform, With<Camera>>,
+    time: Res<Time>,
+) {
+    let mut transform = match camera.get_single_mut() {
+        Ok(transform) => transform,
+        Err(_) => return,
+    };
+
+    // Take back last frame's shake before applying this one's
+    transform.translation -= screen_shake.offset.extend(0.0);
+
+    // Squaring makes small amounts of trauma barely noticeable
+    let strength = screen_shake.trauma * screen_shake.trauma * SCREEN_SHAKE_MAX_OFFSET;
+    let mut rng = thread_rng();
+    screen_shake.offset = Vec2::new(
+        rng.gen_range(-1.0..=1.0) * strength,
+        rng.gen_range(-1.0..=1.0) * strength,
+    );
+    transform.translation += screen_shake.offset.extend(0.0);
+
+    screen_shake.trauma =
+        (screen_shake.trauma - SCREEN_SHAKE_DECAY * time.delta_seconds()).max(0.0);
+}
+
+#[test]
+fn test_cosmetic_effects_rewind() {
+    let key = |frame, source| EffectKey {
+        frame,
+        source,
+        kind: discriminant(&CosmeticEffect::Impact {
+            position: Vec2::ZERO,
+        }),
+    };
+    let effect = CosmeticEffect::Impact {
+        position: Vec2::ZERO,
+    };
+    let mut effects = CosmeticEffects::default();
+
+    // Pushed twice in one pass is still one effect
+    effects.push(key(10, 1), effect);
+    effects.push(key(10, 1), effect);
+    effects.push(key(11, 2), effect);
+    assert_eq!(effects.pending.len(), 2);
+
+    // Pretend both were played
+    for (key, _) in effects.pending.drain(..) {
+        effects.played.insert(key, None);
+    }
+
+    // A rollback to frame 11 puts the second in doubt, and resimulating 10
+    // does not play the first again
+    effects.rewind(11);
+    assert_eq!(effects.unconfirmed, vec![key(11, 2)]);
+    effects.push(key(10, 1), effect);
+    assert!(effects.pending.is_empty());
+
+    // Resimulating 11 confirms it, and anything new still gets played
+    effects.push(key(11, 2), effect);
+    effects.push(key(11, 3), effect);
+    assert!(effects.unconfirmed.is_empty());
+    assert_eq!(effects.pending, vec![(key(11, 3), effect)]);
+}
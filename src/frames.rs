@@ -23,7 +23,7 @@ pub struct ValidatableFrame(pub Frame);
 
 impl Default for ValidatableFrame {
     fn default() -> Self {
-        Self(std::i32::MIN)
+        Self(i32::MIN)
     }
 }
 
@@ -40,6 +40,8 @@ pub struct RollbackStatus {
     pub is_rollback: bool,
     pub is_replay: bool,
     pub rollback_frame: Frame,
+    /// How many frames the latest rollback has to resimulate
+    pub rollback_depth: Frame,
     pub last_frame: Frame,
 }
 
@@ -50,7 +52,11 @@ pub fn update_confirmed_frame(
 ) {
     if let Some(session) = session {
         match &*session {
-            Session::SyncTestSession(_) => confirmed_frame.0 = current_frame.0,
+            // A sync test confirms everything right away, but still rolls back
+            // up to its check distance, which is always under this
+            Session::SyncTestSession(s) => {
+                confirmed_frame.0 = current_frame.0 - s.max_prediction() as Frame
+            }
             Session::P2PSession(s) => confirmed_frame.0 = s.confirmed_frame(),
             Session::SpectatorSession(_) => confirmed_frame.0 = current_frame.0,
         }
@@ -93,6 +99,7 @@ pub fn update_rollback_status(
 
     if rollback_status.is_rollback {
         rollback_status.rollback_frame = current_frame.0;
+        rollback_status.rollback_depth = rollback_status.last_frame - current_frame.0 + 1;
         log::trace!(
             "rollback on {} to {}",
             rollback_status.last_frame,
@@ -0,0 +1,114 @@
+use bevy::{ecs::system::SystemParam, utils::HashMap};
+
+use crate::prelude::*;
+
+/// How far a stick has to move before we take any notice, out of 1
+pub const GAMEPAD_DEADZONE: f32 = 0.25;
+
+/// Which gamepad drives which of our local players.  Render side only, all
+/// the simulation ever sees is the inputs they end up as.
+#[derive(Default, Resource, Debug)]
+pub struct GamepadAssignments(HashMap<PlayerHandle, Gamepad>);
+
+impl GamepadAssignments {
+    pub fn get(&self, handle: PlayerHandle) -> Option<Gamepad> {
+        self.0.get(&handle).copied()
+    }
+}
+
+/// Hands gamepads to local players in the order both turn up, and takes them
+/// back when they are unplugged
+pub fn assign_gamepads(
+    mut assignments: ResMut<GamepadAssignments>,
+    gamepads: Res<Gamepads>,
+    local_handles: Res<LocalHandles>,
+) {
+    assignments.0.retain(|handle, gamepad| {
+        let keep = gamepads.contains(*gamepad) && local_handles.handles.contains(handle);
+        if !keep {
+            info!("Gamepad {:?} is no longer player {}", gamepad, handle);
+        }
+        keep
+    });
+
+    for gamepad in gamepads.iter() {
+        if assignments.0.values().any(|g| *g == gamepad) {
+            continue;
+        }
+        let handle = match local_handles
+            .handles
+            .iter()
+            .find(|handle| !assignments.0.contains_key(handle))
+        {
+            Some(handle) => *handle,
+            None => break,
+        };
+        info!("Gamepad {:?} is now player {}", gamepad, handle);
+        assignments.0.insert(handle, gamepad);
+    }
+}
+
+/// One gamepad, as of this render frame
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GamepadState {
+    /// Left stick, if it is pushed past the deadzone
+    pub movement: Option<Vec2>,
+    /// Right stick angle, if it is pushed past the deadzone
+    pub aim: Option<f32>,
+}
+
+/// The stick, or nothing if it is inside the deadzone.  Radial, so aiming
+/// diagonally is no harder than aiming straight.
+pub fn apply_deadzone(stick: Vec2) -> Option<Vec2> {
+    if stick.length_squared() > GAMEPAD_DEADZONE * GAMEPAD_DEADZONE {
+        Some(stick)
+    } else {
+        None
+    }
+}
+
+/// Reads the sticks of the gamepad assigned to a local player.  Its buttons go
+/// through the [`Controls`] like any other.
+#[derive(SystemParam)]
+pub struct GamepadReader<'w, 's> {
+    assignments: Res<'w, GamepadAssignments>,
+    axes: Res<'w, Axis<GamepadAxis>>,
+    #[system_param(ignore)]
+    _marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> GamepadReader<'w, 's> {
+    pub fn read(&self, handle: PlayerHandle) -> Option<GamepadState> {
+        let gamepad = self.assignments.get(handle)?;
+        let stick = |x, y| {
+            let axis = |axis_type| {
+                self.axes
+                    .get(GamepadAxis::new(gamepad, axis_type))
+                    .unwrap_or(0.0)
+            };
+            apply_deadzone(Vec2::new(axis(x), axis(y)))
+        };
+
+        Some(GamepadState {
+            movement: stick(GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY),
+            aim: stick(GamepadAxisType::RightStickX, GamepadAxisType::RightStickY)
+                .map(|aim| aim.y.atan2(aim.x)),
+        })
+    }
+}
+
+#[test]
+fn test_gamepad_deadzone() {
+    assert_eq!(apply_deadzone(Vec2::ZERO), None);
+    assert_eq!(apply_deadzone(Vec2::new(0.2, 0.1)), None);
+    assert_eq!(
+        apply_deadzone(Vec2::new(0.2, 0.2)),
+        Some(Vec2::new(0.2, 0.2))
+    );
+
+    // A small push on one axis still counts alongside a big one on the other
+    assert_eq!(
+        apply_deadzone(Vec2::new(0.1, -0.9)),
+        Some(Vec2::new(0.1, -0.9))
+    );
+}
@@ -1,6 +1,10 @@
-use crate::prelude::*;
+use crate::{
+    dungeon::{Dungeon, SpawnPointIndex},
+    prelude::*,
+};
 
 #[derive(Component, Default, Reflect)]
+#[reflect(Component)]
 pub struct Health {
     pub hp: usize,
     pub max: usize,
@@ -11,3 +15,102 @@ impl Health {
         Self { hp: max, max }
     }
 }
+
+/// Where a dude is between dying and coming back.  Rolled back along with
+/// [`Health`].
+#[derive(Component, Default, Reflect, Clone, Copy, Debug, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct LifeState {
+    /// Frames until a dead dude respawns, zero while alive
+    pub respawn_frames: usize,
+    /// Frames until a respawned dude can be hurt again
+    pub protection_frames: usize,
+    /// Players whose bullets hit us this frame, one bit per handle.  A bitmask
+    /// so it comes out the same whatever order the bullets are handled in.
+    pub hit_by: u32,
+}
+
+impl LifeState {
+    pub fn is_alive(&self) -> bool {
+        self.respawn_frames == 0
+    }
+
+    /// Whether bullets should hurt
+    pub fn is_hittable(&self) -> bool {
+        self.is_alive() && self.protection_frames == 0
+    }
+}
+
+/// Kills dudes that ran out of health, and brings them back at the next spawn
+/// point once their timer is up.  Runs after bullets have done their damage.
+pub fn update_life_states(
+    dungeon: Res<Dungeon>,
+    mut spawn_index: ResMut<SpawnPointIndex>,
+    mut match_state: ResMut<MatchState>,
+    mut dudes: Query<(
+        &Player,
+        &Rollback,
+        &mut Health,
+        &mut LifeState,
+        &mut Transform,
+    )>,
+    mut effects: EffectWriter,
+) {
+    // Respawns take spawn points in turn, so they have to happen in the same
+    // order for everyone
+    let mut dudes: Vec<_> = dudes.iter_mut().collect();
+    dudes.sort_by_key(|(player, ..)| player.handle);
+
+    for (player, rollback, mut health, mut life, mut transform) in dudes {
+        let hit_by = std::mem::take(&mut life.hit_by);
+        if life.is_alive() {
+            life.protection_frames = life.protection_frames.saturating_sub(1);
+            if health.hp == 0 {
+                log::info!("Player {} died", player.handle);
+                life.respawn_frames = RESPAWN_FRAMES;
+                // Whoever has the lowest handle gets the credit for a shared
+                // kill
+                if hit_by != 0 {
+                    match_state.credit_kill(hit_by.trailing_zeros() as PlayerHandle);
+                }
+                effects.send(
+                    rollback,
+                    CosmeticEffect::Impact {
+                        position: transform.translation.truncate(),
+                    },
+                );
+            }
+            continue;
+        }
+
+        life.respawn_frames -= 1;
+        if life.respawn_frames == 0 {
+            let spawn = dungeon.get_spawn_point(&mut spawn_index);
+            log::info!("Player {} respawned at {:?}", player.handle, spawn);
+            transform.translation = Dungeon::spawn_position(spawn).extend(transform.translation.z);
+            health.hp = health.max;
+            life.protection_frames = SPAWN_PROTECTION_FRAMES;
+        }
+    }
+}
+
+/// Takes dead dudes out of the physics world, and puts them back once they
+/// respawn.  Set every frame, since after a rollback the groups Rapier has
+/// may not match what we last set.
+pub fn update_life_collision_groups(mut dudes: Query<(&LifeState, &mut CollisionGroups)>) {
+    for (life, mut groups) in dudes.iter_mut() {
+        *groups = if life.is_alive() {
+            CollisionGroups::new(COL_DUDE, COL_FILTER_DUDE)
+        } else {
+            CollisionGroups::new(Group::NONE, Group::NONE)
+        };
+    }
+}
+
+/// Hides dead dudes and makes protected ones blink.  Render side only.
+pub fn show_life_states(time: Res<Time>, mut dudes: Query<(&LifeState, &mut Visibility)>) {
+    let blink = ((time.elapsed_seconds() * 10.0) as u32).is_multiple_of(2);
+    for (life, mut visibility) in dudes.iter_mut() {
+        visibility.is_visible = life.is_alive() && (life.protection_frames == 0 || blink);
+    }
+}
@@ -0,0 +1,281 @@
+//! Packs an input struct into as few bits as it needs, so it can go over the
+//! wire as plain bytes.  [`input_schema!`](crate::input_schema!) takes the
+//! struct, with a codec for each field, and generates the wire type, both
+//! directions of the conversion and a test that every field survives them.
+//!
+//! Decoding has to come out exactly the same on every peer, so codecs may
+//! only use IEEE arithmetic that is correctly rounded everywhere.
+
+use std::f32::consts::PI;
+
+use bevy::math::Vec2;
+
+/// How one field is squeezed into bits
+pub trait InputCodec {
+    type Value;
+    /// Bits the field takes up on the wire
+    const BITS: u32;
+
+    /// Must fit in [`InputCodec::BITS`]
+    fn encode(value: &Self::Value) -> u64;
+    /// Only the low [`InputCodec::BITS`] bits are ever set
+    fn decode(bits: u64) -> Self::Value;
+}
+
+/// The low `bits` bits
+pub const fn low_bits(bits: u32) -> u64 {
+    (1 << bits) - 1
+}
+
+/// Adds up the field widths, for [`input_schema!`](crate::input_schema!)
+pub const fn total_bits(bits: &[u32]) -> u32 {
+    let mut total = 0;
+    let mut i = 0;
+    while i < bits.len() {
+        total += bits[i];
+        i += 1;
+    }
+    total
+}
+
+/// A button, held or not
+pub struct Flag;
+
+impl InputCodec for Flag {
+    type Value = bool;
+    const BITS: u32 = 1;
+
+    fn encode(value: &bool) -> u64 {
+        *value as u64
+    }
+
+    fn decode(bits: u64) -> bool {
+        bits != 0
+    }
+}
+
+/// An angle in radians, in `2^BITS` steps around the circle starting from
+/// straight left.  Rounded to the nearest step, so it is never more than half
+/// a step off.
+pub struct Angle<const BITS: u32>;
+
+impl<const BITS: u32> Angle<BITS> {
+    pub const STEPS: u64 = 1 << BITS;
+    pub const RANGE: f32 = 2.0 * PI;
+    pub const OFFSET: f32 = PI;
+}
+
+impl<const BITS: u32> InputCodec for Angle<BITS> {
+    type Value = f32;
+    const BITS: u32 = BITS;
+
+    fn encode(value: &f32) -> u64 {
+        // The top step wraps around to the bottom one, they are both behind
+        ((value + Self::OFFSET) / Self::RANGE * Self::STEPS as f32).round() as u64 % Self::STEPS
+    }
+
+    fn decode(bits: u64) -> f32 {
+        bits as f32 / Self::STEPS as f32 * Self::RANGE - Self::OFFSET
+    }
+}
+
+/// A vector with each axis from -1 to 1, in `2^(AXIS_BITS - 1) - 1` steps
+/// either way.  The lowest value on the wire is one step past -1, so anything
+/// using this has to cope with slightly more than 1 anyway.
+pub struct UnitVec2<const AXIS_BITS: u32>;
+
+impl<const AXIS_BITS: u32> UnitVec2<AXIS_BITS> {
+    /// Steps from 0 to 1
+    pub const STEPS: i64 = (1 << (AXIS_BITS - 1)) - 1;
+
+    fn encode_axis(axis: f32) -> u64 {
+        let steps = (axis * Self::STEPS as f32)
+            .round()
+            .clamp(-(Self::STEPS + 1) as f32, Self::STEPS as f32) as i64;
+        steps as u64 & low_bits(AXIS_BITS)
+    }
+
+    fn decode_axis(bits: u64) -> f32 {
+        let bits = (bits & low_bits(AXIS_BITS)) as i64;
+        let steps = if bits > Self::STEPS {
+            bits - (1 << AXIS_BITS)
+        } else {
+            bits
+        };
+        steps as f32 / Self::STEPS as f32
+    }
+}
+
+impl<const AXIS_BITS: u32> InputCodec for UnitVec2<AXIS_BITS> {
+    type Value = Vec2;
+    const BITS: u32 = 2 * AXIS_BITS;
+
+    fn encode(value: &Vec2) -> u64 {
+        Self::encode_axis(value.x) | (Self::encode_axis(value.y) << AXIS_BITS)
+    }
+
+    fn decode(bits: u64) -> Vec2 {
+        Vec2::new(
+            Self::decode_axis(bits),
+            Self::decode_axis(bits >> AXIS_BITS),
+        )
+    }
+}
+
+/// A small number, like which weapon slot we want
+pub struct UInt<const BITS: u32>;
+
+impl<const BITS: u32> InputCodec for UInt<BITS> {
+    type Value = u32;
+    const BITS: u32 = BITS;
+
+    fn encode(value: &u32) -> u64 {
+        (*value as u64).min(low_bits(BITS))
+    }
+
+    fn decode(bits: u64) -> u32 {
+        bits as u32
+    }
+}
+
+/// Declares an input struct and the wire type it packs into.  Each field
+/// names the [`InputCodec`] it goes through after `as`, and fields are packed
+/// from the lowest bit up in the order they are declared, so new fields go at
+/// the end.  The build fails if they stop fitting in the wire type.
+///
+/// Also generates a test, under the given name, that runs every value each
+/// field can have on the wire through a decode and encode.
+///
+/// ```ignore
+/// input_schema! {
+///     pub struct MyInput {
+///         pub aim: f32 as Angle<10>,
+///         pub jump: bool as Flag,
+///     }
+///     pub struct MyPackedInput([u8; 2]);
+///     test test_my_input_schema;
+/// }
+/// ```
+#[macro_export]
+macro_rules! input_schema {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident: $ty:ty as $codec:ty
+            ),* $(,)?
+        }
+        $(#[$packed_meta:meta])*
+        $packed_vis:vis struct $packed:ident([u8; $bytes:literal]);
+        test $test:ident;
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        $(#[$packed_meta])*
+        #[repr(transparent)]
+        #[derive(
+            Copy, Clone, Debug, Default, PartialEq, Eq, ::bytemuck::Pod, ::bytemuck::Zeroable,
+        )]
+        $packed_vis struct $packed(pub [u8; $bytes]);
+
+        impl $name {
+            /// Bits actually in use on the wire
+            pub const BITS: u32 = $crate::input_schema::total_bits(&[
+                $(<$codec as $crate::input_schema::InputCodec>::BITS),*
+            ]);
+        }
+
+        const _: () = assert!(
+            $name::BITS <= $bytes * 8 && $bytes <= 8,
+            "The input does not fit in its wire type"
+        );
+
+        impl $packed {
+            fn from_bits(bits: u64) -> Self {
+                let mut bytes = [0; $bytes];
+                bytes.copy_from_slice(&bits.to_le_bytes()[..$bytes]);
+                Self(bytes)
+            }
+
+            fn to_bits(self) -> u64 {
+                let mut bytes = [0; 8];
+                bytes[..$bytes].copy_from_slice(&self.0);
+                u64::from_le_bytes(bytes)
+            }
+        }
+
+        impl From<$name> for $packed {
+            fn from(input: $name) -> Self {
+                use $crate::input_schema::InputCodec;
+                let mut bits = 0;
+                let mut offset = 0;
+                $(
+                    bits |= <$codec>::encode(&input.$field) << offset;
+                    offset += <$codec>::BITS;
+                )*
+                let _ = offset;
+                Self::from_bits(bits)
+            }
+        }
+
+        impl From<$packed> for $name {
+            fn from(packed: $packed) -> Self {
+                use $crate::input_schema::{low_bits, InputCodec};
+                let bits = packed.to_bits();
+                let mut offset = 0;
+                $(
+                    let $field = <$codec>::decode((bits >> offset) & low_bits(<$codec>::BITS));
+                    offset += <$codec>::BITS;
+                )*
+                let _ = offset;
+                Self { $($field),* }
+            }
+        }
+
+        #[test]
+        fn $test() {
+            use $crate::input_schema::InputCodec;
+            let mut offset = 0;
+            $(
+                for bits in 0..1u64 << <$codec>::BITS {
+                    // Everything else stays zero, so this also catches fields
+                    // spilling into each other
+                    let packed = $packed::from_bits(bits << offset);
+                    assert_eq!(
+                        $packed::from($name::from(packed)),
+                        packed,
+                        "{} = {:#x}",
+                        stringify!($field),
+                        bits
+                    );
+                }
+                offset += <$codec>::BITS;
+            )*
+            let _ = offset;
+        }
+    };
+}
+
+#[test]
+fn test_codec_round_trips() {
+    fn round_trip<C: InputCodec>() {
+        for bits in 0..1u64 << C::BITS {
+            assert_eq!(C::encode(&C::decode(bits)), bits);
+        }
+    }
+    round_trip::<Flag>();
+    round_trip::<Angle<4>>();
+    round_trip::<Angle<10>>();
+    round_trip::<UnitVec2<3>>();
+    round_trip::<UnitVec2<8>>();
+    round_trip::<UInt<5>>();
+
+    // Out of range values are clamped rather than wrapping around
+    assert_eq!(UInt::<5>::encode(&100), 31);
+    let clamped = UnitVec2::<8>::decode(UnitVec2::<8>::encode(&Vec2::new(5.0, -5.0)));
+    assert_eq!(clamped, Vec2::new(1.0, -128.0 / 127.0));
+}
@@ -0,0 +1,309 @@
+pub mod bullet;
+pub mod camera;
+pub mod checksum;
+pub mod colliders;
+pub mod collisions;
+pub mod constants;
+pub mod controls;
+pub mod dash;
+pub mod desync;
+pub mod diagnostics;
+pub mod dude;
+pub mod dungeon;
+pub mod effects;
+pub mod frames;
+pub mod gamepad;
+pub mod health;
+pub mod input_schema;
+pub mod log_plugin;
+pub mod math;
+pub mod network;
+pub mod physics;
+pub mod rollback;
+pub mod round;
+pub mod smoothing;
+pub mod snapshot;
+pub mod spawn;
+pub mod startup;
+pub mod weapon;
+
+use crate::{
+    bullet::{expire_bullets, restore_bullets, Projectile},
+    dungeon::SpawnPointIndex,
+    prelude::*,
+};
+
+// A prelude to simplify other file imports
+pub mod prelude {
+    pub use crate::checksum::*;
+    pub use crate::colliders::*;
+    pub use crate::collisions::*;
+    pub use crate::constants::*;
+    pub use crate::controls::*;
+    pub use crate::dash::*;
+    pub use crate::desync::*;
+    pub use crate::diagnostics::*;
+    pub use crate::effects::*;
+    pub use crate::frames::*;
+    pub use crate::gamepad::*;
+    pub use crate::health::*;
+    pub use crate::input_schema::*;
+    pub use crate::log_plugin::LogSettings;
+    pub use crate::math::*;
+    pub use crate::network::*;
+    pub use crate::physics::*;
+    pub use crate::rollback::*;
+    pub use crate::round::*;
+    pub use crate::smoothing::*;
+    pub use crate::snapshot::*;
+    pub use crate::spawn::*;
+    pub use crate::startup::*;
+    pub use crate::weapon::*;
+    pub use bevy::log::*;
+    pub use bevy::prelude::*;
+    pub use bevy::tasks::IoTaskPool;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use bevy_framepace::{FramepacePlugin, FramepaceSettings, Limiter};
+
+    pub use bevy_ggrs::{GGRSPlugin, PlayerInputs, Rollback, RollbackIdProvider, Session};
+    pub use bevy_inspector_egui::quick::WorldInspectorPlugin;
+    pub use bevy_inspector_egui_rapier::InspectableRapierPlugin;
+    pub use bevy_rapier2d::prelude::*;
+    pub use bytemuck::{Pod, Zeroable};
+    pub use ggrs::{Frame, InputStatus, PlayerHandle, PlayerType, SessionBuilder};
+    pub use matchbox_socket::WebRtcSocket;
+    pub use rand::{thread_rng, Rng};
+    pub use std::{
+        f32::consts::PI,
+        ops::{Range, RangeInclusive},
+    };
+
+    pub const NUM_PLAYERS: usize = 2;
+    pub const FPS: usize = 60;
+    pub const ROLLBACK_SYSTEMS: &str = "rollback_systems";
+    pub const GAME_SYSTEMS: &str = "game_systems";
+    pub const POOL_SYSTEMS: &str = "pool_systems";
+    pub const CHECKSUM_SYSTEMS: &str = "checksum_systems";
+    pub const PHYSICS_STARTUP: &str = "physics_startup";
+    pub const PHYSICS_SNAPSHOT_STARTUP: &str = "physics_snapshot_startup";
+    pub const MAX_PREDICTION: usize = 5;
+    pub const INPUT_DELAY: usize = 3;
+
+    // How long the countdown before the round runs for
+    pub const COUNTDOWN_SECONDS: usize = 3;
+
+    // How far back we'll keep frame hash info for our other player. This should be
+    // some multiple of MAX_PREDICTION, preferrably 3x, so that we can desync detect
+    // outside the rollback and prediction windows.
+    pub const DESYNC_MAX_FRAMES: usize = 30;
+
+    // TODO: Hey you!!! You, the one reading this!  Yes, you.
+    // Buy gschup a coffee next time you get the chance.
+    // https://ko-fi.com/gschup
+    // They host this match making service for us to use FOR FREE.
+    // It has been an incredibly useful thing I don't have to think about while working
+    // and learning how to implement this stuff and I guarantee it will be for you too.
+    pub const MATCHBOX_ADDR: &str = "wss://match.gschup.dev/bevy-ggrs-rapier-example?next=2";
+    //pub const MATCHBOX_ADDR: &str = "ws://localhost:3536/bevy-ggrs-rapier-example?next=2";
+    // TODO: Maybe update this room name (bevy-ggrs-rapier-example) so we don't test with each other :-)
+}
+
+/// Our GGRS rollback schedule: frame bookkeeping, then game logic, then
+/// physics, then saving the physics state.
+pub fn rollback_schedule() -> Schedule {
+    Schedule::default()
+        // It is imperative that this executes first, always.  Yes, I know about `.after()`.
+        // I'm putting this here in case you end up adding any `Commands` to this step,
+        // which I think must flush at all costs before we enter the regular game logic
+        .with_stage(
+            ROLLBACK_SYSTEMS,
+            SystemStage::parallel()
+                // Just strictly ordered so we have ordered comparable
+                // logging.  Could be optimized if the logger info was
+                // synthesized into it's own system or something
+                // Exclusive, so this runs before anything else in the stage
+                .with_system(restore_spawn_pool)
+                .with_system(start_rollback_timer.before(update_current_frame))
+                .with_system(update_current_frame)
+                .with_system(update_current_session_frame.after(update_current_frame))
+                .with_system(update_confirmed_frame.after(update_current_session_frame))
+                // The three above must actually come before we update rollback status
+                .with_system(update_rollback_status.after(update_confirmed_frame))
+                // These three must actually come after we update rollback status
+                .with_system(update_validatable_frame.after(update_rollback_status))
+                .with_system(rollback_rapier_context.after(update_rollback_status))
+                .with_system(restore_bullets.after(rollback_rapier_context))
+                .with_system(count_rollbacks.after(update_rollback_status))
+                .with_system(rewind_cosmetic_effects.after(update_rollback_status)),
+        )
+        // Add our game logic and systems here.  If it impacts what the
+        // physics engine should consider, do it here.
+        .with_stage_after(
+            ROLLBACK_SYSTEMS,
+            GAME_SYSTEMS,
+            SystemStage::parallel()
+                // Anything that reacts to collisions must come after this
+                .with_system(dispatch_collision_events)
+                .with_system(apply_inputs)
+                // The `frame_validator` relies on the execution of `apply_inputs` and must come after.
+                // It could happen anywhere else, I just stuck it here to be clear.
+                // If this is causing your game to quit, you have a bug!
+                .with_system(frame_validator.after(apply_inputs))
+                .with_system(force_update_rollbackables)
+                // Everything from here on moves dudes around or brings them
+                // back to life, so it has to happen in the same order relative
+                // to their inputs on every peer
+                .with_system(
+                    expire_bullets
+                        .after(apply_inputs)
+                        .after(dispatch_collision_events),
+                )
+                .with_system(update_life_states.after(expire_bullets))
+                .with_system(update_match_state.after(update_life_states))
+                // A rematch brings everyone back to life
+                .with_system(update_life_collision_groups.after(update_match_state)),
+        )
+        // Anything the game logic is done with goes back into the spawn pool
+        // here, after all of its `Commands` have been applied.
+        .with_stage_after(
+            GAME_SYSTEMS,
+            POOL_SYSTEMS,
+            SystemStage::parallel()
+                .with_system(return_to_pool)
+                .with_system(release_rollback_ids),
+        )
+        // The next 3 stages are all bevy_rapier stages.  Best to leave these in order.
+        .with_stage_after(
+            POOL_SYSTEMS,
+            PhysicsStages::SyncBackend,
+            SystemStage::parallel().with_system_set(sync_backend_systems()),
+        )
+        .with_stage_after(
+            PhysicsStages::SyncBackend,
+            PhysicsStages::StepSimulation,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::StepSimulation),
+            ),
+        )
+        .with_stage_after(
+            PhysicsStages::StepSimulation,
+            PhysicsStages::Writeback,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::Writeback),
+            ),
+        )
+        // This must execute after writeback to store the RapierContext
+        .with_stage_after(
+            PhysicsStages::Writeback,
+            CHECKSUM_SYSTEMS,
+            SystemStage::parallel()
+                .with_system(save_rapier_context)
+                .with_system(record_collision_events)
+                .with_system(track_visual_corrections)
+                .with_system(
+                    stop_rollback_timer
+                        .after(save_rapier_context)
+                        .after(record_collision_events)
+                        .after(track_visual_corrections),
+                ),
+        )
+}
+
+/// Our GGRS plugin with everything we roll back registered, minus the input
+/// system, which is up to whoever is providing the inputs
+pub fn ggrs_plugin() -> GGRSPlugin<GGRSConfig> {
+    GGRSPlugin::<GGRSConfig>::new()
+        .with_update_frequency(FPS)
+        .register_rollback_resource::<PhysicsRollbackState>()
+        .register_rollback_resource::<CurrentFrame>()
+        .register_rollback_component::<Health>()
+        .register_rollback_component::<LifeState>()
+        .register_rollback_component::<Dash>()
+        .register_rollback_component::<Weapon>()
+        // Store everything that Rapier updates in its Writeback stage
+        .register_rollback_component::<GlobalTransform>()
+        .register_rollback_component::<Transform>()
+        .register_rollback_component::<Velocity>()
+        .register_rollback_component::<Sleeping>()
+        // Game stuff
+        .register_rollback_resource::<RoundStart>()
+        .register_rollback_resource::<MatchState>()
+        .register_rollback_component::<DeterministicSpawn>()
+        .register_rollback_component::<Projectile>()
+        .register_rollback_resource::<NextSpawnIndex>()
+        .register_rollback_resource::<SpawnPointIndex>()
+        .with_rollback_schedule(rollback_schedule())
+}
+
+/// Builds the physics world for everything spawned at startup and snapshots
+/// it as frame 0, so even a rollback to the very first frame has a complete
+/// state to load.  Expects `reset_rapier` and `respawn_all` in the regular
+/// startup stage.
+pub fn add_physics_startup(app: &mut App) {
+    app.add_startup_stage_after(
+        StartupStage::PostStartup,
+        PHYSICS_STARTUP,
+        // This propagates transforms itself before handing anything to Rapier
+        SystemStage::single_threaded().with_system_set(
+            RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsStages::SyncBackend),
+        ),
+    )
+    .add_startup_stage_after(
+        PHYSICS_STARTUP,
+        PHYSICS_SNAPSHOT_STARTUP,
+        SystemStage::single_threaded().with_system(seed_physics_snapshots),
+    );
+}
+
+/// bevy_rapier's [`PhysicsStages::SyncBackend`] systems, minus `sync_removals`.
+/// That one drops the body of every entity that lost its
+/// [`RapierRigidBodyHandle`] since the last render frame, so it would also drop
+/// the new body of a pool entity that a resimulation handed back and then took
+/// out again.  Only the spawn pool ever takes physics components off an entity,
+/// and [`return_to_pool`] and [`restore_bullets`] already keep Rapier in step
+/// for those themselves.
+pub fn sync_backend_systems() -> SystemSet {
+    use bevy::transform::transform_propagate_system;
+    use bevy_rapier2d::plugin::systems::*;
+
+    // Same order as `RapierPhysicsPlugin::get_systems`
+    SystemSet::new()
+        .with_system(update_character_controls)
+        .with_system(transform_propagate_system.after(update_character_controls))
+        .with_system(init_async_colliders.after(transform_propagate_system))
+        .with_system(apply_scale.after(init_async_colliders))
+        .with_system(apply_collider_user_changes.after(apply_scale))
+        .with_system(apply_rigid_body_user_changes.after(apply_collider_user_changes))
+        .with_system(apply_joint_user_changes.after(apply_rigid_body_user_changes))
+        .with_system(init_rigid_bodies.after(apply_joint_user_changes))
+        .with_system(
+            init_colliders
+                .after(init_rigid_bodies)
+                .after(init_async_colliders),
+        )
+        .with_system(init_joints.after(init_colliders))
+        .with_system(apply_initial_rigid_body_impulses.after(init_colliders))
+}
+
+/// The Rapier configuration our rollback schedule expects
+pub fn rapier_configuration() -> RapierConfiguration {
+    RapierConfiguration {
+        // The timestep_mode MUST be fixed
+        timestep_mode: TimestepMode::Fixed {
+            dt: 1. / FPS as f32,
+            substeps: 1,
+        },
+
+        // This should work with gravity, too.  It is fun for testing.
+        gravity: Vec2::ZERO,
+
+        // Turn off query pipeline since this example does not use it
+        query_pipeline_active: false,
+
+        // Do not check internal structures for transform changes
+        force_update_from_transform_changes: true,
+
+        ..default()
+    }
+}
@@ -4,7 +4,8 @@ pub fn random_point_in_circle(rng: &mut impl Rng, radius: f32) -> Vec2 {
     let t = 2.0 * PI * rng.gen_range(0.0..1.0);
     let u = rng.gen_range(0.0..2.0);
     let r = if u > 1.0 { 2.0 - u } else { u };
-    Vec2::new(radius * r * t.cos(), radius * r * t.sin())
+    // The dungeon is generated on every peer, so this has to agree too
+    direction(t) * (radius * r)
 }
 
 pub fn iter_float(range: RangeInclusive<f32>, step: f32) -> impl Iterator<Item = f32> {
@@ -17,3 +18,18 @@ pub fn iter_float(range: RangeInclusive<f32>, step: f32) -> impl Iterator<Item =
 pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + (b - a) * t
 }
+
+/// Unit vector pointing along `angle`.  Anything the simulation depends on
+/// must use this rather than `f32::sin_cos`: the std versions call into
+/// whatever the platform provides, and native and wasm peers would disagree
+/// in the last bits.  `libm` is the same software implementation everywhere.
+pub fn direction(angle: f32) -> Vec2 {
+    Vec2::new(libm::cosf(angle), libm::sinf(angle))
+}
+
+/// Same as [`Quat::from_rotation_z`], but deterministic across platforms like
+/// [`direction`]
+pub fn rotation_z(angle: f32) -> Quat {
+    let half = angle * 0.5;
+    Quat::from_xyzw(0.0, 0.0, libm::sinf(half), libm::cosf(half))
+}
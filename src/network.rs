@@ -16,7 +16,16 @@ pub fn connect(mut commands: Commands) {
     commands.insert_resource(WebRtcSocketWrapper(Some(socket)));
 }
 
-pub fn update_matchbox_socket(commands: Commands, mut socket_res: ResMut<WebRtcSocketWrapper>) {
+/// Waits for the weapons to load before starting, everything we send to the
+/// other player carries their checksum
+pub fn update_matchbox_socket(
+    commands: Commands,
+    mut socket_res: ResMut<WebRtcSocketWrapper>,
+    weapons: Option<Res<Weapons>>,
+) {
+    if weapons.is_none() {
+        return;
+    }
     if let Some(socket) = socket_res.0.as_mut() {
         socket.accept_new_connections(); // needs mut
         if socket.players().len() >= NUM_PLAYERS {
@@ -1,20 +1,8 @@
+use bevy::{utils::HashMap, window::CursorMoved};
 use ggrs::Config;
 
 use crate::{bullet::BulletBundle, prelude::*};
 
-#[rustfmt::skip]
-mod input_bits {
-    pub const ANGLE_RANGE: f32 = 2.0 * std::f32::consts::PI;
-    pub const ANGLE_OFFSET: f32 = std::f32::consts::PI;
-
-    pub const UP:        u16 = 0b0000010000000000;
-    pub const DOWN:      u16 = 0b0000100000000000;
-    pub const LEFT:      u16 = 0b0001000000000000;
-    pub const RIGHT:     u16 = 0b0010000000000000;
-    pub const PRIMARY:   u16 = 0b0100000000000000;
-    pub const SECONDARY: u16 = 0b1000000000000000;
-    pub const ANGLE:     u16 = 0b0000001111111111;
-}
 /// GGRS player handle, we use this to associate GGRS handles back to our [`Entity`]
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Component)]
 pub struct Player {
@@ -37,63 +25,38 @@ impl Config for GGRSConfig {
     type Address = String;
 }
 
-#[derive(Clone, Copy, Debug)]
-struct PlayerInput {
-    pub angle: f32,
-    pub primary: bool,
-    pub secondary: bool,
-    pub up: bool,
-    pub down: bool,
-    pub left: bool,
-    pub right: bool,
-}
-
-impl PlayerInput {
-    pub fn movement_vec(&self) -> Vec2 {
-        Vec2::new(
-            (self.right as i8 - self.left as i8) as f32 * PLAYER_MOVE_SPEED,
-            (self.up as i8 - self.down as i8) as f32 * PLAYER_MOVE_SPEED,
-        )
+crate::input_schema! {
+    /// What a player wants to do on a frame, as the simulation sees it.  New
+    /// actions go at the end.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct PlayerInput {
+        pub angle: f32 as Angle<10>,
+        /// Where we want to go, see [`PlayerInput::movement_vec`]
+        pub movement: Vec2 as UnitVec2<8>,
+        pub primary: bool as Flag,
+        pub secondary: bool as Flag,
+        pub reload: bool as Flag,
+        /// The slot in [`Weapons`] we want to be holding
+        pub weapon: u32 as UInt<2>,
     }
-}
-
-impl From<u16> for PlayerInput {
-    fn from(input: u16) -> Self {
-        PlayerInput {
-            angle: (input & input_bits::ANGLE) as f32 / 1024.0 * input_bits::ANGLE_RANGE
-                - input_bits::ANGLE_OFFSET,
-            primary: input & input_bits::PRIMARY != 0,
-            secondary: input & input_bits::SECONDARY != 0,
-            up: input & input_bits::UP != 0,
-            down: input & input_bits::DOWN != 0,
-            left: input & input_bits::LEFT != 0,
-            right: input & input_bits::RIGHT != 0,
-        }
-    }
-}
 
-impl From<PlayerInput> for u16 {
-    fn from(input: PlayerInput) -> Self {
-        let angle =
-            ((input.angle + input_bits::ANGLE_OFFSET) / input_bits::ANGLE_RANGE * 1024.0) as u16;
+    /// A player's input as it goes over the wire
+    pub struct PackedInput([u8; 4]);
 
-        let primary = if input.primary {
-            input_bits::PRIMARY
-        } else {
-            0
-        };
+    test test_player_input_schema;
+}
 
-        let secondary = if input.secondary {
-            input_bits::SECONDARY
+impl PlayerInput {
+    /// How far we move this frame.  Normalizing is plain IEEE arithmetic and
+    /// a square root, so this is exact to the bit on every platform.
+    pub fn movement_vec(&self) -> Vec2 {
+        // Full tilt diagonally is no faster than full tilt straight ahead
+        let movement = if self.movement.length_squared() > 1.0 {
+            self.movement.normalize()
         } else {
-            0
+            self.movement
         };
-
-        let up = if input.up { input_bits::UP } else { 0 };
-        let down = if input.down { input_bits::DOWN } else { 0 };
-        let left = if input.left { input_bits::LEFT } else { 0 };
-        let right = if input.right { input_bits::RIGHT } else { 0 };
-        angle | primary | secondary | up | down | left | right
+        movement * PLAYER_MOVE_SPEED
     }
 }
 
@@ -102,10 +65,12 @@ impl From<PlayerInput> for u16 {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
 pub struct GGRSInput {
     // The input from our player
-    pub input: u16,
+    pub input: PackedInput,
 
     // Desync detection
     pub last_confirmed_hash: u16,
+    /// Our [`Weapons::checksum`], everyone has to be playing with the same ones
+    pub weapons_checksum: u16,
     pub last_confirmed_frame: Frame,
     // Ok, so I know what you're thinking:
     // > "That's not input!"
@@ -118,14 +83,141 @@ pub struct GGRSInput {
     // https://github.com/cscorley/ggrs/tree/arbitrary-messages-0.8
 }
 
+/// What happened on the render side since we last sent an input.  The render
+/// loop and the rollback schedule do not tick in step, so a click that starts
+/// and ends between two input polls would otherwise never be seen.
+#[derive(Default, Resource)]
+pub struct InputAccumulator(HashMap<PlayerHandle, LatchedInput>);
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct LatchedInput {
+    primary: bool,
+    secondary: bool,
+    /// The last angle we were aiming at, from the cursor or a stick
+    angle: f32,
+    /// The weapon slot we picked last
+    weapon: u32,
+}
+
+impl InputAccumulator {
+    /// Called once per render frame, for each device driving `handle`
+    pub fn latch(
+        &mut self,
+        handle: PlayerHandle,
+        primary: bool,
+        secondary: bool,
+        angle: Option<f32>,
+    ) {
+        let latched = self.0.entry(handle).or_default();
+        latched.primary |= primary;
+        latched.secondary |= secondary;
+        if let Some(angle) = angle {
+            latched.angle = angle;
+        }
+    }
+
+    /// Moves on to the next of `count` weapon slots, back to the first after
+    /// the last one
+    pub fn next_weapon(&mut self, handle: PlayerHandle, count: usize) {
+        let latched = self.0.entry(handle).or_default();
+        latched.weapon = (latched.weapon + 1) % count.max(1) as u32;
+    }
+
+    /// Everything latched for `handle` since the last call, for its next
+    /// input.  The angle and weapon stick around, it is still where we were
+    /// aiming and what we picked.
+    pub fn drain(&mut self, handle: PlayerHandle) -> (bool, bool, f32, u32) {
+        let latched = self.0.entry(handle).or_default();
+        (
+            std::mem::take(&mut latched.primary),
+            std::mem::take(&mut latched.secondary),
+            latched.angle,
+            latched.weapon,
+        )
+    }
+}
+
+/// Runs every render frame, right after bevy has read the input devices.
+/// The cursor aims for our first player until a stick is pushed, and a stick
+/// aims for whoever has it until the mouse moves again.  A released stick
+/// leaves the aim where it was.
+#[allow(clippy::too_many_arguments)]
+pub fn accumulate_input(
+    mut accumulator: ResMut<InputAccumulator>,
+    mut cursor_aims: Local<bool>,
+    mut cursor_moved: EventReader<CursorMoved>,
+    actions: ActionInput,
+    gamepads: GamepadReader,
+    windows: Res<Windows>,
+    local_handles: Res<LocalHandles>,
+    weapons: Option<Res<Weapons>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    dudes: Query<(&Player, &Transform)>,
+) {
+    if cursor_moved.iter().count() > 0 {
+        *cursor_aims = true;
+    }
+
+    for (i, &handle) in local_handles.handles.iter().enumerate() {
+        let stick_aim = gamepads.read(handle).and_then(|gamepad| gamepad.aim);
+        let angle = if stick_aim.is_some() {
+            if i == 0 {
+                *cursor_aims = false;
+            }
+            stick_aim
+        } else if i == 0 && *cursor_aims {
+            aim_angle(handle, &windows, &camera, &dudes)
+        } else {
+            None
+        };
+
+        accumulator.latch(
+            handle,
+            actions.pressed(handle, Action::Primary),
+            actions.just_pressed(handle, Action::Secondary),
+            angle,
+        );
+        if let Some(weapons) = &weapons {
+            if actions.just_pressed(handle, Action::NextWeapon) {
+                accumulator.next_weapon(handle, weapons.count());
+            }
+        }
+    }
+}
+
+/// Angle from our dude to wherever the cursor is in the world.  The camera
+/// trails behind the dude, so the middle of the window is not where they are.
+/// Nothing if there is no cursor or no dude to aim from.
+fn aim_angle(
+    handle: PlayerHandle,
+    windows: &Windows,
+    camera: &Query<(&Camera, &GlobalTransform)>,
+    dudes: &Query<(&Player, &Transform)>,
+) -> Option<f32> {
+    let cursor = windows.get_primary()?.cursor_position()?;
+    let (camera, camera_transform) = camera.get_single().ok()?;
+    let target = camera
+        .viewport_to_world(camera_transform, cursor)?
+        .origin
+        .truncate();
+
+    let (_, dude) = dudes.iter().find(|(player, _)| player.handle == handle)?;
+    let diff = target - dude.translation.truncate();
+    // Right on top of the dude, any angle is as good as any other
+    if diff == Vec2::ZERO {
+        return None;
+    }
+    Some(diff.y.atan2(diff.x))
+}
+
 pub fn input(
     handle: In<PlayerHandle>,
-    keyboard_input: Res<Input<KeyCode>>,
-    mouse_input: Res<Input<MouseButton>>,
-    physics_enabled: Res<PhysicsEnabled>,
     mut hashes: ResMut<FrameHashes>,
     validatable_frame: Res<ValidatableFrame>,
-    windows: Res<Windows>,
+    mut accumulator: ResMut<InputAccumulator>,
+    actions: ActionInput,
+    gamepads: GamepadReader,
+    weapons: Res<Weapons>,
 ) -> GGRSInput {
     let mut last_confirmed_frame = ggrs::NULL_FRAME;
     let mut last_confirmed_hash = 0;
@@ -150,59 +242,61 @@ pub fn input(
         }
     }
 
-    // Do not do anything until physics are live
-    if !physics_enabled.0 {
-        return GGRSInput {
-            input: 0,
-            last_confirmed_frame,
-            last_confirmed_hash,
-        };
-    }
+    let handle = handle.0;
+    let (primary, secondary, angle, weapon) = accumulator.drain(handle);
+    let gamepad = gamepads.read(handle).unwrap_or_default();
+    let pressed = |action| actions.pressed(handle, action);
 
-    let window = windows.get_primary().unwrap();
-    let v = Vec2::new(window.width() / 2.0, window.height() / 2.0);
+    let keys = Vec2::new(
+        (pressed(Action::Right) as i8 - pressed(Action::Left) as i8) as f32,
+        (pressed(Action::Up) as i8 - pressed(Action::Down) as i8) as f32,
+    );
 
     let input = PlayerInput {
-        angle: window
-            .cursor_position()
-            .map(|target| {
-                let diff = target - v;
-                diff.y.atan2(diff.x)
-            })
-            .unwrap_or(0.0),
-        primary: mouse_input.just_pressed(MouseButton::Left),
-        secondary: mouse_input.just_pressed(MouseButton::Right),
-        up: keyboard_input.pressed(KeyCode::W),
-        down: keyboard_input.pressed(KeyCode::S),
-        left: keyboard_input.pressed(KeyCode::A),
-        right: keyboard_input.pressed(KeyCode::D),
+        angle,
+        // A stick can do anything the keys can, and more
+        movement: gamepad.movement.unwrap_or(keys),
+        // Held rather than clicked, the weapon decides when that is a shot.
+        // Still held if it was at any point since the last input.
+        primary: primary || pressed(Action::Primary),
+        secondary,
+        reload: pressed(Action::Reload),
+        weapon,
     };
 
     GGRSInput {
         input: input.into(),
         last_confirmed_frame,
         last_confirmed_hash,
+        weapons_checksum: weapons.checksum(),
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn apply_inputs(
-    mut commands: Commands,
-    mut query: Query<(&mut KinematicCharacterController, &mut Transform, &Player)>,
+    mut query: Query<(
+        &mut KinematicCharacterController,
+        &mut Transform,
+        &Player,
+        &LifeState,
+        &mut Dash,
+        &mut Weapon,
+        &Rollback,
+    )>,
     inputs: Res<PlayerInputs<GGRSConfig>>,
     mut hashes: ResMut<RxFrameHashes>,
     local_handles: Res<LocalHandles>,
-    physics_enabled: Res<PhysicsEnabled>,
-    mut rip: ResMut<RollbackIdProvider>,
+    current_frame: Res<CurrentFrame>,
+    round_start: Res<RoundStart>,
+    match_state: Res<MatchState>,
+    weapons: Res<Weapons>,
     asset_server: Res<AssetServer>,
-    spawn_pool: Query<(Entity, &DeterministicSpawn)>,
+    mut spawner: DeterministicSpawner,
+    mut effects: EffectWriter,
 ) {
-    // Get our entities and sort them by the spawn component index
-    let mut sorted_spawn_pool: Vec<(Entity, &DeterministicSpawn)> = spawn_pool.iter().collect();
-    sorted_spawn_pool.sort_by_key(|e| e.1.index);
-    // Get the Entities in reverse for easy popping
-    let mut sorted_entity_pool: Vec<Entity> = sorted_spawn_pool.iter().map(|p| p.0).rev().collect();
-
-    for (mut controller, mut transform, player) in query.iter_mut() {
+    for (mut controller, mut transform, player, life, mut dash, mut weapon, rollback) in
+        query.iter_mut()
+    {
         let (game_input, input_status) = inputs[player.handle];
         // Check the desync for this player if they're not a local handle
         // Did they send us some goodies?
@@ -230,15 +324,27 @@ pub fn apply_inputs(
             }
         }
 
+        // Predictions are copies of what they sent before, or nothing at all
+        // if they have not sent anything yet
+        if !local_handles.handles.contains(&player.handle) && input_status == InputStatus::Confirmed
+        {
+            assert_eq!(
+                game_input.weapons_checksum,
+                weapons.checksum(),
+                "Player {} has different weapons than we do",
+                player.handle
+            );
+        }
+
         // On to the boring stuff
-        let input: PlayerInput = match input_status {
+        let packed = match input_status {
             InputStatus::Confirmed => game_input.input,
             InputStatus::Predicted => game_input.input,
-            InputStatus::Disconnected => 0, // disconnected players do nothing
-        }
-        .into();
+            InputStatus::Disconnected => PackedInput::default(), // disconnected players do nothing
+        };
+        let input = PlayerInput::from(packed);
 
-        if u16::from(input) > 0 {
+        if packed != PackedInput::default() {
             // Useful for desync observing
             log::trace!(
                 "input {:?} from {}: {:?}",
@@ -248,24 +354,71 @@ pub fn apply_inputs(
             );
         }
 
-        // Do not do anything until physics are live
-        // This is a poor mans emulation to stop accidentally tripping velocity updates
-        if !physics_enabled.0 {
+        // Everyone stands still until the countdown is over, and once the
+        // match is
+        if !round_start.is_live(current_frame.0) || match_state.is_over() {
+            continue;
+        }
+
+        // The dead wait for their respawn
+        if !life.is_alive() {
             continue;
         }
 
-        controller.translation = Some(input.movement_vec());
+        // Dash along where we are going, or where we are looking if we are
+        // standing still
+        if input.secondary {
+            let movement = input.movement_vec();
+            let dash_direction = if movement == Vec2::ZERO {
+                direction(input.angle)
+            } else {
+                movement
+            };
+            dash.start(dash_direction);
+        }
+        controller.translation = Some(dash.tick().unwrap_or_else(|| input.movement_vec()));
+
+        transform.rotation = rotation_z(input.angle);
 
-        transform.rotation = Quat::from_rotation_z(input.angle);
+        // Switching is instant, and the new one comes fully loaded
+        let slot = input.weapon as usize;
+        if slot != weapon.definition && slot < weapons.count() {
+            *weapon = weapons.equip(slot);
+        }
 
-        if input.primary {
-            let id = Rollback::new(rip.next_id());
+        let definition = weapons.get(weapon.definition);
+        weapon.tick(definition);
+        if input.reload {
+            weapon.reload(definition);
+        }
+        if weapon.pull_trigger(definition, input.primary) {
+            for angle in definition.pellet_angles(input.angle) {
+                let bullet_bundle = BulletBundle::new(
+                    player.handle,
+                    &weapons,
+                    weapon.definition,
+                    transform.translation.truncate(),
+                    angle,
+                    asset_server.load(definition.texture.as_str()),
+                );
+                // Both of us run out at the same time, so skipping the rest
+                // of the shot is still deterministic
+                if let Err(e) = spawner.spawn(bullet_bundle) {
+                    log::error!("Player {} could not fire: {}", player.handle, e);
+                    break;
+                }
+            }
 
-            let bullet_bundle = BulletBundle::new(&*transform, id, asset_server.load("bullet.png"));
-            commands
-                .entity(sorted_entity_pool.pop().unwrap())
-                .insert(bullet_bundle)
-                .remove::<DeterministicSpawn>();
+            effects.send(
+                rollback,
+                CosmeticEffect::MuzzleFlash {
+                    position: transform.translation.truncate(),
+                    rotation: transform.rotation,
+                },
+            );
+            if local_handles.handles.contains(&player.handle) {
+                effects.send(rollback, CosmeticEffect::ScreenShake { trauma: 0.3 });
+            }
         }
     }
 }
@@ -283,32 +436,122 @@ pub fn force_update_rollbackables(
 }
 
 #[test]
-fn test_input_encode_decode() {
-    for angle in iter_float(0.0..=input_bits::ANGLE_RANGE, 0.1) {
-        for left in [true, false] {
-            for right in [true, false] {
-                for up in [true, false] {
-                    for down in [true, false] {
-                        for primary in [true, false] {
-                            for secondary in [true, false] {
-                                let input = PlayerInput {
-                                    angle,
-                                    left,
-                                    right,
-                                    up,
-                                    down,
-                                    primary,
-                                    secondary,
-                                };
-                                assert_eq!(
-                                    u16::from(input),
-                                    u16::from(PlayerInput::from(u16::from(input)))
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+fn test_input_quantization_error() {
+    let neutral = PlayerInput::from(PackedInput::default());
+
+    // Aim is never more than half a step off, wherever we point
+    let angle_step = Angle::<10>::RANGE / Angle::<10>::STEPS as f32;
+    for angle in iter_float(-PI..=PI, 0.0007) {
+        let input = PlayerInput { angle, ..neutral };
+        let decoded = PlayerInput::from(PackedInput::from(input)).angle;
+        let error = (decoded - angle).rem_euclid(Angle::<10>::RANGE);
+        let error = error.min(Angle::<10>::RANGE - error);
+        assert!(
+            error <= angle_step / 2.0 + 1e-5,
+            "{} came back as {}",
+            angle,
+            decoded
+        );
+    }
+
+    // Movement is never more than half a step off on either axis
+    let move_step = 1.0 / UnitVec2::<8>::STEPS as f32;
+    for x in iter_float(-1.0..=1.0, 0.003) {
+        for y in iter_float(-1.0..=1.0, 0.003) {
+            let movement = Vec2::new(x, y);
+            let input = PlayerInput {
+                movement,
+                ..neutral
+            };
+            let decoded = PlayerInput::from(PackedInput::from(input)).movement;
+            let error = (decoded - movement).abs();
+            assert!(
+                error.max_element() <= move_step / 2.0 + 1e-6,
+                "{} came back as {}",
+                movement,
+                decoded
+            );
         }
     }
 }
+
+#[test]
+fn test_input_accumulator() {
+    let mut accumulator = InputAccumulator::default();
+    assert_eq!(accumulator.drain(0), (false, false, 0.0, 0));
+
+    // A click over two render frames between polls is still a click
+    accumulator.latch(0, true, true, Some(1.0));
+    accumulator.latch(0, false, false, None);
+    assert_eq!(accumulator.drain(0), (true, true, 1.0, 0));
+
+    // But only the once, and the aim stays put
+    assert_eq!(accumulator.drain(0), (false, false, 1.0, 0));
+
+    // Every player has their own
+    accumulator.latch(1, false, true, Some(2.0));
+    assert_eq!(accumulator.drain(0), (false, false, 1.0, 0));
+    assert_eq!(accumulator.drain(1), (false, true, 2.0, 0));
+
+    // The weapon we picked sticks, and wraps around after the last one
+    accumulator.next_weapon(0, 3);
+    assert_eq!(accumulator.drain(0), (false, false, 1.0, 1));
+    assert_eq!(accumulator.drain(0), (false, false, 1.0, 1));
+    accumulator.next_weapon(0, 3);
+    accumulator.next_weapon(0, 3);
+    assert_eq!(accumulator.drain(0), (false, false, 1.0, 0));
+    assert_eq!(accumulator.drain(1), (false, false, 2.0, 0));
+}
+
+#[test]
+fn test_input_kinematics_bits() {
+    // Encoded angle, then the exact bits we expect for the decoded angle, its
+    // direction and the z and w of its rotation.  These must never change
+    // between platforms, or between versions of anything we depend on.
+    let neutral = PlayerInput::from(PackedInput::default());
+    #[rustfmt::skip]
+    let expected: [(u16, u32, [u32; 2], [u32; 2]); 6] = [
+        (0,    0xc0490fdb, [0xbf800000, 0x33bbbd2e], [0xbf800000, 0xb33bbd2e]),
+        (256,  0xbfc90fdb, [0xb33bbd2e, 0xbf800000], [0xbf3504f3, 0x3f3504f3]),
+        (512,  0x00000000, [0x3f800000, 0x00000000], [0x00000000, 0x3f800000]),
+        (640,  0x3f490fdc, [0x3f3504f2, 0x3f3504f4], [0x3ec3ef16, 0x3f6c835e]),
+        (768,  0x3fc90fda, [0x33a22169, 0x3f800000], [0x3f3504f3, 0x3f3504f4]),
+        (1023, 0x4048ab53, [0xbf7ffec4, 0x3bc90ef2], [0x3f7fffb1, 0x3b490f30]),
+    ];
+
+    for (bits, angle, dir, rot) in expected {
+        let input = PlayerInput {
+            angle: Angle::<10>::decode(bits as u64),
+            ..neutral
+        };
+        assert_eq!(input.angle.to_bits(), angle, "angle of {}", bits);
+
+        let d = direction(input.angle);
+        assert_eq!([d.x.to_bits(), d.y.to_bits()], dir, "direction of {}", bits);
+
+        let r = rotation_z(input.angle);
+        assert_eq!([r.z.to_bits(), r.w.to_bits()], rot, "rotation of {}", bits);
+        assert_eq!([r.x, r.y], [0.0, 0.0]);
+    }
+
+    // Full tilt one way, then diagonally, which has to be normalized
+    #[rustfmt::skip]
+    let expected: [([f32; 2], [u32; 2]); 2] = [
+        ([1.0, 0.0],  [0x40a00000, 0x00000000]),
+        ([-1.0, 1.0], [0xc0624630, 0x40624630]),
+    ];
+
+    for (stick, bits) in expected {
+        let input = PlayerInput::from(PackedInput::from(PlayerInput {
+            movement: Vec2::from(stick),
+            ..neutral
+        }));
+        let movement = input.movement_vec();
+        assert_eq!(
+            [movement.x.to_bits(), movement.y.to_bits()],
+            bits,
+            "movement of {:?}",
+            stick
+        );
+    }
+}
@@ -0,0 +1,381 @@
+use bevy_egui::{egui, EguiContext};
+
+use crate::{
+    dungeon::{Dungeon, SpawnPointIndex},
+    prelude::*,
+};
+
+/// How long everyone has to stand still before the round starts.  This also
+/// gives GGRS time to settle, which used to be the job of a "load screen".
+pub const COUNTDOWN_FRAMES: Frame = (COUNTDOWN_SECONDS * FPS) as Frame;
+
+/// How long "GO!" stays up after the countdown
+const GO_FRAMES: Frame = FPS as Frame / 2;
+
+/// The frame the round starts on.  Rolled back, so a rollback into the
+/// countdown sees exactly the countdown it saw the first time around.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Resource, Hash, Reflect)]
+#[reflect(Hash, Resource, PartialEq)]
+pub struct RoundStart(pub Frame);
+
+impl Default for RoundStart {
+    fn default() -> Self {
+        Self(COUNTDOWN_FRAMES)
+    }
+}
+
+impl RoundStart {
+    /// Whether inputs count on `frame`
+    pub fn is_live(&self, frame: Frame) -> bool {
+        frame >= self.0
+    }
+
+    /// Whole seconds left on the countdown as of `frame`, rounded up
+    pub fn seconds_left(&self, frame: Frame) -> Frame {
+        let frames_left = (self.0 - frame).max(0);
+        (frames_left + FPS as Frame - 1) / FPS as Frame
+    }
+}
+
+/// How a match is won.  Not rolled back, and every peer has to be playing by
+/// the same rules or they will disagree about when the match is over.
+#[derive(Copy, Clone, Debug, Resource)]
+pub struct MatchRules {
+    /// Kills that win the match, or zero to play until the time is up
+    pub frag_limit: usize,
+    /// How long a match lasts once the countdown is over, or zero for no limit
+    pub time_limit: Frame,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            frag_limit: FRAG_LIMIT,
+            time_limit: (MATCH_SECONDS * FPS) as Frame,
+        }
+    }
+}
+
+impl MatchRules {
+    /// Frames left in the match as of `frame`, if there is a time limit
+    pub fn frames_left(&self, round_start: &RoundStart, frame: Frame) -> Option<Frame> {
+        if self.time_limit == 0 {
+            return None;
+        }
+        let elapsed = (frame - round_start.0).max(0);
+        Some((self.time_limit - elapsed).max(0))
+    }
+}
+
+/// Everything about the match that is not on a dude.  Rolled back, kills can
+/// be taken back just like the bullets that scored them.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Resource, Hash, Reflect)]
+#[reflect(Hash, Resource, PartialEq)]
+pub struct MatchState {
+    /// Kills scored by each player, by handle
+    pub kills: [usize; NUM_PLAYERS],
+    /// The frame the match ended on, or [`ggrs::NULL_FRAME`] while it is on
+    pub ended: Frame,
+    /// Who has asked for a rematch since it ended
+    pub rematch: [bool; NUM_PLAYERS],
+    /// Whose trigger was down on the last frame, so asking for a rematch
+    /// takes a fresh pull and not a trigger still held from the fight
+    pub trigger_held: [bool; NUM_PLAYERS],
+}
+
+impl Default for MatchState {
+    fn default() -> Self {
+        Self {
+            kills: [0; NUM_PLAYERS],
+            ended: ggrs::NULL_FRAME,
+            rematch: [false; NUM_PLAYERS],
+            trigger_held: [false; NUM_PLAYERS],
+        }
+    }
+}
+
+impl MatchState {
+    pub fn is_over(&self) -> bool {
+        self.ended != ggrs::NULL_FRAME
+    }
+
+    /// Kills only count while the match is on, not from bullets that were
+    /// still flying when it ended
+    pub fn credit_kill(&mut self, handle: PlayerHandle) {
+        if !self.is_over() {
+            self.kills[handle] += 1;
+        }
+    }
+
+    /// Takes whose trigger is held this frame, and returns who just pulled it
+    pub fn pull_triggers(&mut self, held: [bool; NUM_PLAYERS]) -> [bool; NUM_PLAYERS] {
+        let was_held = std::mem::replace(&mut self.trigger_held, held);
+        std::array::from_fn(|handle| held[handle] && !was_held[handle])
+    }
+
+    /// The player with the most kills, unless it is a tie
+    pub fn leader(&self) -> Option<PlayerHandle> {
+        let most = *self.kills.iter().max()?;
+        let mut leaders = (0..NUM_PLAYERS).filter(|handle| self.kills[*handle] == most);
+        match (leaders.next(), leaders.next()) {
+            (Some(handle), None) => Some(handle),
+            _ => None,
+        }
+    }
+}
+
+/// Ends the match once someone reaches the frag limit or the time is up, and
+/// starts the next one once everyone has clicked for a rematch.  Runs after
+/// deaths have been handled, so the last kill counts.
+#[allow(clippy::too_many_arguments)]
+pub fn update_match_state(
+    rules: Res<MatchRules>,
+    current_frame: Res<CurrentFrame>,
+    inputs: Res<PlayerInputs<GGRSConfig>>,
+    dungeon: Res<Dungeon>,
+    mut round_start: ResMut<RoundStart>,
+    mut match_state: ResMut<MatchState>,
+    mut spawn_index: ResMut<SpawnPointIndex>,
+    mut dudes: Query<(&Player, &mut Health, &mut LifeState, &mut Transform)>,
+) {
+    let frame = current_frame.0;
+
+    // Kept up to date while the match is on too, so whoever is still firing
+    // when it ends has to let go and click again
+    let held = std::array::from_fn(|handle| PlayerInput::from(inputs[handle].0.input).primary);
+    let pulled = match_state.pull_triggers(held);
+
+    if !match_state.is_over() {
+        let frag_limit_reached =
+            rules.frag_limit > 0 && match_state.kills.iter().any(|k| *k >= rules.frag_limit);
+        let time_up = rules.frames_left(&round_start, frame) == Some(0);
+        if frag_limit_reached || time_up {
+            log::info!("Match over on frame {}: {:?}", frame, match_state.kills);
+            match_state.ended = frame;
+        }
+        return;
+    }
+
+    for handle in 0..NUM_PLAYERS {
+        let (_, input_status) = inputs[handle];
+        // Nobody is going to wait on a player that left
+        let ready = match input_status {
+            InputStatus::Disconnected => true,
+            _ => pulled[handle],
+        };
+        if ready && !match_state.rematch[handle] {
+            log::info!("Player {} wants a rematch", handle);
+            match_state.rematch[handle] = true;
+        }
+    }
+    if !match_state.rematch.iter().all(|ready| *ready) {
+        return;
+    }
+
+    log::info!("Rematch starting on frame {}", frame);
+    *match_state = MatchState {
+        trigger_held: held,
+        ..default()
+    };
+    round_start.0 = frame + COUNTDOWN_FRAMES;
+
+    // Back to where everyone started, in the same order as at startup
+    *spawn_index = SpawnPointIndex::default();
+    let mut dudes: Vec<_> = dudes.iter_mut().collect();
+    dudes.sort_by_key(|(player, ..)| player.handle);
+    for (_, mut health, mut life, mut transform) in dudes {
+        let spawn = dungeon.get_spawn_point(&mut spawn_index);
+        transform.translation = Dungeon::spawn_position(spawn).extend(transform.translation.z);
+        health.hp = health.max;
+        *life = LifeState::default();
+    }
+}
+
+/// Shows the countdown in the middle of the screen.  Only the render side
+/// reads it, whatever frame we happen to be on.
+pub fn countdown_overlay(
+    mut egui_context: ResMut<EguiContext>,
+    round_start: Option<Res<RoundStart>>,
+    current_frame: Option<Res<CurrentFrame>>,
+    session: Option<Res<Session<GGRSConfig>>>,
+) {
+    let (round_start, current_frame) = match (round_start, current_frame) {
+        (Some(round_start), Some(current_frame)) => (round_start, current_frame),
+        _ => return,
+    };
+
+    let text = if session.is_none() {
+        "Waiting for players".to_string()
+    } else if !round_start.is_live(current_frame.0) {
+        round_start.seconds_left(current_frame.0).to_string()
+    } else if current_frame.0 < round_start.0 + GO_FRAMES {
+        "GO!".to_string()
+    } else {
+        return;
+    };
+
+    egui::Area::new("countdown")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .interactable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(
+                egui::RichText::new(text)
+                    .size(64.0)
+                    .strong()
+                    .color(egui::Color32::BLACK),
+            );
+        });
+}
+
+/// Scores and time left along the top of the screen
+pub fn match_overlay(
+    mut egui_context: ResMut<EguiContext>,
+    rules: Res<MatchRules>,
+    match_state: Option<Res<MatchState>>,
+    round_start: Option<Res<RoundStart>>,
+    current_frame: Option<Res<CurrentFrame>>,
+    session: Option<Res<Session<GGRSConfig>>>,
+) {
+    let (match_state, round_start, current_frame) = match (match_state, round_start, current_frame)
+    {
+        (Some(match_state), Some(round_start), Some(current_frame)) if session.is_some() => {
+            (match_state, round_start, current_frame)
+        }
+        _ => return,
+    };
+
+    let mut text = match_state
+        .kills
+        .iter()
+        .enumerate()
+        .map(|(handle, kills)| format!("Player {}: {}", handle, kills))
+        .collect::<Vec<_>>()
+        .join("    ");
+    if let Some(frames_left) = rules.frames_left(&round_start, current_frame.0) {
+        let seconds_left = (frames_left + FPS as Frame - 1) / FPS as Frame;
+        text += &format!("    {}:{:02}", seconds_left / 60, seconds_left % 60);
+    }
+
+    egui::Area::new("match")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 8.0])
+        .interactable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(
+                egui::RichText::new(text)
+                    .size(20.0)
+                    .strong()
+                    .color(egui::Color32::BLACK),
+            );
+        });
+}
+
+/// Final scores once the match is over.  Asking for a rematch is a click like
+/// any other, it has to go through the inputs so every peer sees it.
+pub fn results_screen(
+    mut egui_context: ResMut<EguiContext>,
+    match_state: Option<Res<MatchState>>,
+    local_handles: Option<Res<LocalHandles>>,
+) {
+    let (match_state, local_handles) = match (match_state, local_handles) {
+        (Some(match_state), Some(local_handles)) if match_state.is_over() => {
+            (match_state, local_handles)
+        }
+        _ => return,
+    };
+
+    egui::Window::new("Match over")
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            let headline = match match_state.leader() {
+                Some(handle) if local_handles.handles.contains(&handle) => "You win!".to_string(),
+                Some(handle) => format!("Player {} wins", handle),
+                None => "Draw".to_string(),
+            };
+            ui.heading(headline);
+
+            for (handle, kills) in match_state.kills.iter().enumerate() {
+                let you = if local_handles.handles.contains(&handle) {
+                    " (you)"
+                } else {
+                    ""
+                };
+                let ready = if match_state.rematch[handle] {
+                    ", ready"
+                } else {
+                    ""
+                };
+                ui.label(format!(
+                    "Player {}{}: {} kills{}",
+                    handle, you, kills, ready
+                ));
+            }
+
+            ui.separator();
+            ui.label("Fire to play again");
+        });
+}
+
+#[test]
+fn test_match_leader() {
+    let mut match_state = MatchState::default();
+    assert_eq!(match_state.leader(), None);
+
+    match_state.credit_kill(1);
+    assert_eq!(match_state.leader(), Some(1));
+
+    match_state.credit_kill(0);
+    assert_eq!(match_state.leader(), None);
+
+    // Kills after the end do not count
+    match_state.ended = 100;
+    match_state.credit_kill(0);
+    assert_eq!(match_state.kills, [1, 1]);
+}
+
+#[test]
+fn test_rematch_needs_a_fresh_pull() {
+    let mut match_state = MatchState::default();
+    assert_eq!(match_state.pull_triggers([true, false]), [true, false]);
+
+    // Holding on does not count again, letting go and pulling does
+    assert_eq!(match_state.pull_triggers([true, true]), [false, true]);
+    assert_eq!(match_state.pull_triggers([false, true]), [false, false]);
+    assert_eq!(match_state.pull_triggers([true, true]), [true, false]);
+}
+
+#[test]
+fn test_match_time_left() {
+    let fps = FPS as Frame;
+    let round_start = RoundStart(3 * fps);
+    let rules = MatchRules {
+        frag_limit: 0,
+        time_limit: 60 * fps,
+    };
+    // The clock does not run during the countdown
+    assert_eq!(rules.frames_left(&round_start, 0), Some(60 * fps));
+    assert_eq!(rules.frames_left(&round_start, 4 * fps), Some(59 * fps));
+    assert_eq!(rules.frames_left(&round_start, 63 * fps), Some(0));
+    assert_eq!(rules.frames_left(&round_start, 100 * fps), Some(0));
+
+    let rules = MatchRules {
+        frag_limit: 5,
+        time_limit: 0,
+    };
+    assert_eq!(rules.frames_left(&round_start, 100 * fps), None);
+}
+
+#[test]
+fn test_countdown_seconds() {
+    let fps = FPS as Frame;
+    let round_start = RoundStart(3 * fps);
+    assert_eq!(round_start.seconds_left(0), 3);
+    assert_eq!(round_start.seconds_left(fps - 1), 3);
+    assert_eq!(round_start.seconds_left(fps), 2);
+    assert_eq!(round_start.seconds_left(3 * fps - 1), 1);
+    assert_eq!(round_start.seconds_left(3 * fps), 0);
+    assert_eq!(round_start.seconds_left(10 * fps), 0);
+    assert!(!round_start.is_live(3 * fps - 1));
+    assert!(round_start.is_live(3 * fps));
+}
@@ -0,0 +1,122 @@
+use bevy::transform::TransformSystem;
+
+use crate::prelude::*;
+
+/// How quickly a correction fades out, per second.  Higher is snappier.
+const SMOOTHING_RATE: f32 = 15.0;
+
+/// Corrections bigger than this are a respawn or some other teleport, not a
+/// misprediction, and are not worth hiding
+const SMOOTHING_SNAP_DISTANCE: f32 = 4.0 * TILE_SIZE as f32;
+
+/// Render-only smoothing for entities that rollbacks move around.  Not rolled
+/// back: it remembers where the entity was on each frame we simulated, so once
+/// a rollback resimulates the frame we last showed, we know how far it jumped
+/// and can ease it over from there instead.
+///
+/// Only the translation is smoothed, rotation comes straight from the inputs.
+#[derive(Component, Default, Clone, Debug)]
+pub struct SmoothedVisual {
+    /// Where we drew it, relative to where it is
+    offset: Vec3,
+    /// The most recent frame we simulated and where the entity was on it
+    latest: Option<(Frame, Vec3)>,
+    /// The simulated transform we drew over this render frame
+    simulated: Option<GlobalTransform>,
+}
+
+#[derive(Resource)]
+pub struct VisualSmoothing {
+    pub enabled: bool,
+}
+
+impl Default for VisualSmoothing {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+/// Draws [`SmoothedVisual`] entities where they appear to be rather than where
+/// they are.  F4 toggles it.
+pub struct VisualSmoothingPlugin;
+
+impl Plugin for VisualSmoothingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(VisualSmoothing::default())
+            .add_system_to_stage(CoreStage::First, restore_simulated_transforms)
+            .add_system(toggle_visual_smoothing)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                apply_visual_smoothing.after(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Runs at the end of every frame in the rollback schedule.  When we get to
+/// the frame we had already simulated last, anything that moved since is a
+/// correction.
+pub fn track_visual_corrections(
+    current_frame: Res<CurrentFrame>,
+    smoothing: Option<Res<VisualSmoothing>>,
+    mut query: Query<(&Transform, &mut SmoothedVisual)>,
+) {
+    let enabled = smoothing.is_some_and(|s| s.enabled);
+    for (transform, mut visual) in query.iter_mut() {
+        match visual.latest {
+            Some((frame, translation)) if frame == current_frame.0 && enabled => {
+                visual.offset += translation - transform.translation;
+            }
+            Some((frame, _)) if frame > current_frame.0 => continue,
+            _ => {}
+        }
+        visual.latest = Some((current_frame.0, transform.translation));
+    }
+}
+
+/// Draws over the propagated transforms, after everything else is done with
+/// them for this render frame
+pub fn apply_visual_smoothing(
+    smoothing: Res<VisualSmoothing>,
+    time: Res<Time>,
+    mut query: Query<(&mut GlobalTransform, &mut SmoothedVisual)>,
+) {
+    let decay = (-SMOOTHING_RATE * time.delta_seconds()).exp();
+    for (mut global_transform, mut visual) in query.iter_mut() {
+        if !smoothing.enabled || visual.offset.length() > SMOOTHING_SNAP_DISTANCE {
+            visual.offset = Vec3::ZERO;
+        }
+        visual.offset *= decay;
+        if visual.offset.length_squared() < 0.01 {
+            visual.offset = Vec3::ZERO;
+            continue;
+        }
+
+        let simulated = *global_transform;
+        visual.simulated = Some(simulated);
+
+        let mut transform = simulated.compute_transform();
+        transform.translation += visual.offset;
+        // Nobody but the renderer may notice this
+        *global_transform.bypass_change_detection() = GlobalTransform::from(transform);
+    }
+}
+
+/// Puts the simulated transforms back before anything else runs, so neither
+/// bevy_ggrs nor Rapier ever see what we drew
+pub fn restore_simulated_transforms(mut query: Query<(&mut GlobalTransform, &mut SmoothedVisual)>) {
+    for (mut global_transform, mut visual) in query.iter_mut() {
+        if let Some(simulated) = visual.simulated.take() {
+            *global_transform.bypass_change_detection() = simulated;
+        }
+    }
+}
+
+pub fn toggle_visual_smoothing(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut smoothing: ResMut<VisualSmoothing>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F4) {
+        smoothing.enabled = !smoothing.enabled;
+        info!("Visual smoothing: {}", smoothing.enabled);
+    }
+}
@@ -0,0 +1,331 @@
+use bevy::utils::{Duration, Instant};
+use miniz_oxide::{
+    deflate::core::{
+        compress_to_output, create_comp_flags_from_zip_params, CompressorOxide, TDEFLFlush,
+        TDEFLStatus,
+    },
+    inflate::{
+        core::{decompress, inflate_flags, DecompressorOxide},
+        TINFLStatus,
+    },
+};
+
+use crate::prelude::*;
+
+/// How many serialized physics states we keep around.  GGRS will never load a
+/// frame further back than our prediction window, so we need one slot for
+/// each predicted frame plus one for the frame we are currently on.
+pub const PHYSICS_SNAPSHOT_SLOTS: usize = MAX_PREDICTION + 1;
+
+/// Deflate level for the snapshot deltas.  Deltas are mostly zeroes, so the
+/// fastest level already gets the bulk of the savings.
+const SNAPSHOT_COMPRESSION_LEVEL: i32 = 1;
+
+/// A serialized [`RapierContext`] as it was at the end of `frame`, stored as a
+/// compressed XOR delta against the confirmed state from `base_frame`
+#[derive(Debug)]
+pub struct PhysicsSnapshot {
+    pub frame: Frame,
+    pub base_frame: Frame,
+    pub raw_len: usize,
+    pub bytes: Vec<u8>,
+}
+
+impl Default for PhysicsSnapshot {
+    fn default() -> Self {
+        Self {
+            frame: ggrs::NULL_FRAME,
+            base_frame: ggrs::NULL_FRAME,
+            raw_len: 0,
+            bytes: Vec::new(),
+        }
+    }
+}
+
+/// An uncompressed, confirmed physics state that snapshots are diffed against
+#[derive(Debug)]
+struct PhysicsBase {
+    frame: Frame,
+    bytes: Vec<u8>,
+}
+
+/// What the last [`PhysicsSnapshots::store`] cost us
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PhysicsSnapshotStats {
+    /// Size of the serialized context
+    pub raw_bytes: usize,
+    /// Size of the compressed delta we kept for this frame
+    pub stored_bytes: usize,
+    /// Everything the ring is holding on to: all deltas plus their bases
+    pub total_bytes: usize,
+    /// Time spent serializing, diffing and compressing
+    pub encode_time: Duration,
+}
+
+/// Frame-indexed ring of physics snapshots.  This is deliberately NOT
+/// registered with bevy_ggrs: it would clone every buffer on every save.
+/// Instead, each slot keeps its allocation and gets overwritten in place as
+/// frames come around again, and rollbacks look up the frame they need.
+///
+/// Consecutive physics states barely differ, so rather than keeping every one
+/// in full we keep the most recent confirmed state raw and store each frame as
+/// a deflated XOR against it.  Confirmed states can never be rolled back past,
+/// so any frame we could be asked to load was diffed against a base we still
+/// hold.
+#[derive(Resource)]
+pub struct PhysicsSnapshots {
+    slots: [PhysicsSnapshot; PHYSICS_SNAPSHOT_SLOTS],
+    bases: Vec<PhysicsBase>,
+    // Buffers of bases we no longer need, kept to avoid reallocating
+    spare: Vec<Vec<u8>>,
+    // Serialization and decode scratch space
+    scratch: Vec<u8>,
+    compressor: Box<CompressorOxide>,
+    decompressor: Box<DecompressorOxide>,
+    pub stats: PhysicsSnapshotStats,
+}
+
+impl Default for PhysicsSnapshots {
+    fn default() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| PhysicsSnapshot::default()),
+            bases: Vec::with_capacity(PHYSICS_SNAPSHOT_SLOTS + 1),
+            spare: Vec::new(),
+            scratch: Vec::new(),
+            compressor: Box::new(CompressorOxide::new(create_comp_flags_from_zip_params(
+                SNAPSHOT_COMPRESSION_LEVEL,
+                0,
+                0,
+            ))),
+            decompressor: Box::default(),
+            stats: PhysicsSnapshotStats::default(),
+        }
+    }
+}
+
+impl PhysicsSnapshots {
+    fn slot(frame: Frame) -> usize {
+        frame.rem_euclid(PHYSICS_SNAPSHOT_SLOTS as Frame) as usize
+    }
+
+    /// Serializes `rapier` into the slot for `frame`, reusing the buffer that
+    /// was there before.  The newest frame up to `confirmed_frame` that we
+    /// hold becomes the base for the frames after it.  Returns the checksum of
+    /// the new state.
+    pub fn store(
+        &mut self,
+        frame: Frame,
+        confirmed_frame: Frame,
+        rapier: &RapierContext,
+    ) -> Option<u16> {
+        let start = Instant::now();
+
+        // Without mispredictions we never resimulate a confirmed frame, so
+        // this is where bases usually come from
+        self.promote_base(confirmed_frame);
+
+        self.scratch.clear();
+        if let Err(e) = bincode::serialize_into(&mut self.scratch, rapier) {
+            log::error!("Could not serialize physics for frame {}: {}", frame, e);
+            self.slots[Self::slot(frame)].frame = ggrs::NULL_FRAME;
+            return None;
+        }
+        // Checksums are always over the raw state, they are what we compare
+        // with our peers
+        let checksum = fletcher16(&self.scratch);
+
+        if frame <= confirmed_frame {
+            self.push_base(frame);
+        }
+
+        let base = self.bases.last();
+        let base_frame = base.map_or(ggrs::NULL_FRAME, |b| b.frame);
+        if let Some(base) = base {
+            for (byte, base_byte) in self.scratch.iter_mut().zip(base.bytes.iter()) {
+                *byte ^= base_byte;
+            }
+        }
+
+        let snapshot = &mut self.slots[Self::slot(frame)];
+        snapshot.bytes.clear();
+        self.compressor.reset();
+        let (status, _) = compress_to_output(
+            &mut self.compressor,
+            &self.scratch,
+            TDEFLFlush::Finish,
+            |chunk| {
+                snapshot.bytes.extend_from_slice(chunk);
+                true
+            },
+        );
+        if status != TDEFLStatus::Done {
+            log::error!(
+                "Could not compress physics for frame {}: {:?}",
+                frame,
+                status
+            );
+            snapshot.frame = ggrs::NULL_FRAME;
+            return None;
+        }
+
+        snapshot.frame = frame;
+        snapshot.base_frame = base_frame;
+        snapshot.raw_len = self.scratch.len();
+        let stored_bytes = snapshot.bytes.len();
+
+        self.prune_bases();
+
+        self.stats = PhysicsSnapshotStats {
+            raw_bytes: self.scratch.len(),
+            stored_bytes,
+            total_bytes: self.slots.iter().map(|s| s.bytes.len()).sum::<usize>()
+                + self.bases.iter().map(|b| b.bytes.len()).sum::<usize>(),
+            encode_time: start.elapsed(),
+        };
+
+        Some(checksum)
+    }
+
+    /// Rebuilds the serialized context for `frame` from its delta, if we still
+    /// have it.  The returned bytes are only valid until the next call.
+    pub fn load(&mut self, frame: Frame) -> Option<&[u8]> {
+        let snapshot = &self.slots[Self::slot(frame)];
+        if snapshot.frame != frame {
+            return None;
+        }
+
+        self.scratch.clear();
+        self.scratch.resize(snapshot.raw_len, 0);
+        self.decompressor.init();
+        let (status, _, out) = decompress(
+            &mut self.decompressor,
+            &snapshot.bytes,
+            &mut self.scratch,
+            0,
+            inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+        );
+        if status != TINFLStatus::Done || out != snapshot.raw_len {
+            log::error!(
+                "Could not decompress physics for frame {}: {:?}",
+                frame,
+                status
+            );
+            return None;
+        }
+
+        if let Some(base) = self.bases.iter().find(|b| b.frame == snapshot.base_frame) {
+            for (byte, base_byte) in self.scratch.iter_mut().zip(base.bytes.iter()) {
+                *byte ^= base_byte;
+            }
+        } else if snapshot.base_frame != ggrs::NULL_FRAME {
+            log::error!(
+                "Lost base frame {} for physics at frame {}",
+                snapshot.base_frame,
+                frame
+            );
+            return None;
+        }
+
+        Some(&self.scratch)
+    }
+
+    /// Decodes the newest stored frame that has since been confirmed into a
+    /// new base, if it is newer than the one we have
+    fn promote_base(&mut self, confirmed_frame: Frame) {
+        let newest = self.bases.last().map_or(ggrs::NULL_FRAME, |b| b.frame);
+        let frame = self
+            .slots
+            .iter()
+            .map(|s| s.frame)
+            .filter(|&f| f != ggrs::NULL_FRAME && f > newest && f <= confirmed_frame)
+            .max();
+
+        if let Some(frame) = frame {
+            if self.load(frame).is_some() {
+                self.push_base(frame);
+            }
+        }
+    }
+
+    /// Keeps a raw copy of the freshly serialized state in `scratch`
+    fn push_base(&mut self, frame: Frame) {
+        // Resimulating a confirmed frame gives us the same state again
+        self.bases.retain(|b| b.frame != frame);
+
+        let mut bytes = self.spare.pop().unwrap_or_default();
+        bytes.clear();
+        bytes.extend_from_slice(&self.scratch);
+        self.bases.push(PhysicsBase { frame, bytes });
+    }
+
+    /// Drops every base except the newest one that no slot is diffed against
+    fn prune_bases(&mut self) {
+        let newest = self.bases.last().map(|b| b.frame);
+        let mut i = 0;
+        while i < self.bases.len() {
+            let frame = self.bases[i].frame;
+            if Some(frame) != newest && !self.slots.iter().any(|s| s.base_frame == frame) {
+                self.spare.push(self.bases.remove(i).bytes);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_store_load() {
+    let mut snapshots = PhysicsSnapshots::default();
+    let mut rapier = RapierContext::default();
+    let mut expected = Vec::new();
+
+    for frame in 0..(PHYSICS_SNAPSHOT_SLOTS * 3) as Frame {
+        // Make every frame serialize a little differently
+        rapier.integration_parameters.max_ccd_substeps = frame as usize;
+        let bytes = bincode::serialize(&rapier).unwrap();
+        let confirmed_frame = frame - frame % 4;
+        let checksum = snapshots.store(frame, confirmed_frame, &rapier);
+        assert_eq!(checksum, Some(fletcher16(&bytes)));
+        expected.push(bytes);
+    }
+
+    let last = expected.len() as Frame - 1;
+    for frame in 0..=last {
+        let loaded = snapshots.load(frame).map(|b| b.to_vec());
+        if frame > last - PHYSICS_SNAPSHOT_SLOTS as Frame {
+            assert_eq!(loaded.as_ref(), Some(&expected[frame as usize]));
+        } else {
+            assert_eq!(loaded, None);
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_base_follows_confirmed_frame() {
+    let mut snapshots = PhysicsSnapshots::default();
+    let mut rapier = RapierContext::default();
+    let mut expected = Vec::new();
+
+    // Like a session that never mispredicts: every frame is saved once, while
+    // it is still a prediction, and only confirmed a few frames later.  Frame
+    // 0 is the seed, which starts out confirmed.
+    for frame in 0..(PHYSICS_SNAPSHOT_SLOTS * 3) as Frame {
+        rapier.integration_parameters.max_ccd_substeps = frame as usize;
+        expected.push(bincode::serialize(&rapier).unwrap());
+        let confirmed_frame = (frame - 2).max(0);
+        snapshots.store(frame, confirmed_frame, &rapier);
+
+        let base = snapshots.bases.last().map(|b| b.frame);
+        assert_eq!(base, Some(confirmed_frame));
+        assert_eq!(
+            snapshots.slots[PhysicsSnapshots::slot(frame)].base_frame,
+            confirmed_frame
+        );
+    }
+
+    let last = expected.len() as Frame - 1;
+    for frame in (last + 1 - PHYSICS_SNAPSHOT_SLOTS as Frame)..=last {
+        let loaded = snapshots.load(frame).map(|b| b.to_vec());
+        assert_eq!(loaded.as_ref(), Some(&expected[frame as usize]));
+    }
+}
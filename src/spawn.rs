@@ -1,11 +1,27 @@
+use bevy::{
+    ecs::system::{EntityCommands, SystemChangeTick, SystemParam},
+    utils::HashMap,
+};
+
 use crate::prelude::*;
 
+/// How many placeholder entities we spawn before anything else gets a chance
+pub const SPAWN_POOL_SIZE: usize = 10001;
+
 /// A marker component for spawning first thing when the app launches.  This
 /// just contains some arbitrary data, it actually isn't critical (it's used to
 /// sort, but we could also use [`Entity`])
-#[derive(Component)]
+///
+/// While an entity has this it is sitting in the pool.  It is rolled back, but
+/// only entities out of the pool carry a [`Rollback`] id, and those that came
+/// back recently, see [`release_rollback_ids`].  The rest sit in the pool
+/// untouched by rollbacks, see [`restore_spawn_pool`].
+#[derive(Component, Default, Reflect)]
+#[reflect(Component)]
 pub struct DeterministicSpawn {
     pub index: usize,
+    /// The frame it was last handed back on
+    pub returned: Frame,
 }
 
 #[derive(Bundle)]
@@ -17,8 +33,352 @@ pub struct DeterministicSpawnBundle {
 impl DeterministicSpawnBundle {
     pub fn new(index: usize) -> Self {
         Self {
-            spawn: DeterministicSpawn { index },
+            spawn: DeterministicSpawn { index, ..default() },
             name: Name::new(format!("Deterministic Spawn {}", index)),
         }
     }
 }
+
+/// The rollback id that goes with each pool entity, handed out once by
+/// [`prepare_spawn_pool`] so every peer agrees on them.  This is not rolled
+/// back.
+#[derive(Resource, Default)]
+pub struct SpawnPool {
+    rollback_ids: HashMap<Entity, u32>,
+    /// The [`DeterministicSpawn`] index each entity had when it was last taken
+    /// out, see [`restore_spawn_pool`]
+    taken: HashMap<Entity, usize>,
+}
+
+impl SpawnPool {
+    /// The rollback id of a pool entity, whether it is out of the pool or not
+    pub fn rollback_id(&self, entity: Entity) -> Option<u32> {
+        self.rollback_ids.get(&entity).copied()
+    }
+}
+
+/// The index the next entity handed back to the pool gets.  Returned entities
+/// queue up behind everything that is already free, so an entity is not
+/// reused on the very frame it was given back.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Resource, Hash, Reflect)]
+#[reflect(Hash, Resource, PartialEq)]
+pub struct NextSpawnIndex(pub usize);
+
+impl Default for NextSpawnIndex {
+    fn default() -> Self {
+        Self(SPAWN_POOL_SIZE)
+    }
+}
+
+/// Hands a pool entity back at the end of [`GAME_SYSTEMS`].  `strip` removes
+/// whatever was inserted when it was taken out of the pool.
+#[derive(Component)]
+pub struct ReturnToPool {
+    pub strip: fn(&mut EntityCommands),
+}
+
+/// The pool ran dry.  Something is taking entities out without ever handing
+/// them back.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SpawnPoolEmpty {
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for SpawnPoolEmpty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "all {} deterministic spawn pool entities are in use",
+            self.capacity
+        )
+    }
+}
+
+impl std::error::Error for SpawnPoolEmpty {}
+
+/// The free entities for a single run of a system, in the order we hand them
+/// out.  Taking an entity only removes [`DeterministicSpawn`] once commands are
+/// applied, so we have to remember what we already gave away.
+#[derive(Default)]
+pub struct SpawnPoolCursor {
+    change_tick: u32,
+    free: Vec<(Entity, usize)>,
+}
+
+/// Hands out pool entities in index order.  Use this instead of spawning
+/// anything that Rapier or bevy_ggrs will see.
+#[derive(SystemParam)]
+pub struct DeterministicSpawner<'w, 's> {
+    commands: Commands<'w, 's>,
+    pool: ResMut<'w, SpawnPool>,
+    spawn_pool: Query<'w, 's, (Entity, &'static DeterministicSpawn)>,
+    cursor: Local<'s, SpawnPoolCursor>,
+    tick: SystemChangeTick,
+}
+
+impl<'w, 's> DeterministicSpawner<'w, 's> {
+    /// Takes the next entity out of the pool, gives it its [`Rollback`] id and
+    /// inserts `bundle` on it
+    pub fn spawn<'a>(
+        &'a mut self,
+        bundle: impl Bundle,
+    ) -> Result<EntityCommands<'w, 's, 'a>, SpawnPoolEmpty> {
+        let entity = self.take()?;
+        let rollback_id = self
+            .pool
+            .rollback_id(entity)
+            .expect("Pool entities get their rollback ids at startup");
+
+        let mut e = self.commands.entity(entity);
+        e.remove::<DeterministicSpawn>()
+            .insert(Rollback::new(rollback_id))
+            .insert(bundle);
+        Ok(e)
+    }
+
+    /// Like [`DeterministicSpawner::spawn`], but for things that never change
+    /// once spawned, like the walls.  They get no [`Rollback`] id, so bevy_ggrs
+    /// does not snapshot them.  Never hand these back to the pool.
+    pub fn spawn_static<'a>(
+        &'a mut self,
+        bundle: impl Bundle,
+    ) -> Result<EntityCommands<'w, 's, 'a>, SpawnPoolEmpty> {
+        let entity = self.take()?;
+
+        let mut e = self.commands.entity(entity);
+        e.remove::<DeterministicSpawn>().insert(bundle);
+        Ok(e)
+    }
+
+    /// How many entities the pool was created with
+    pub fn capacity(&self) -> usize {
+        SPAWN_POOL_SIZE
+    }
+
+    /// How many entities can still be taken during this system run
+    pub fn available(&mut self) -> usize {
+        self.refresh();
+        self.cursor.free.len()
+    }
+
+    /// How many entities are out of the pool right now
+    pub fn in_use(&mut self) -> usize {
+        self.capacity() - self.available()
+    }
+
+    fn take(&mut self) -> Result<Entity, SpawnPoolEmpty> {
+        self.refresh();
+        let capacity = self.capacity();
+        let (entity, index) = self.cursor.free.pop().ok_or(SpawnPoolEmpty { capacity })?;
+        self.pool.taken.insert(entity, index);
+        Ok(entity)
+    }
+
+    fn refresh(&mut self) {
+        // The change tick is new every time the system runs
+        if self.cursor.change_tick == self.tick.change_tick() {
+            return;
+        }
+
+        // Get our entities and sort them by the spawn component index
+        let mut sorted_spawn_pool: Vec<(Entity, &DeterministicSpawn)> =
+            self.spawn_pool.iter().collect();
+        sorted_spawn_pool.sort_by_key(|e| e.1.index);
+
+        // Get the Entities in reverse for easy popping
+        self.cursor.free.clear();
+        self.cursor
+            .free
+            .extend(sorted_spawn_pool.iter().map(|p| (p.0, p.1.index)).rev());
+        self.cursor.change_tick = self.tick.change_tick();
+    }
+}
+
+/// Sets aside a [`Rollback`] id for every pool entity, in pool order.  Runs in
+/// [`StartupStage::PreStartup`], before anything takes entities out.
+pub fn prepare_spawn_pool(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    spawn_pool: Query<(Entity, &DeterministicSpawn)>,
+) {
+    let mut sorted_spawn_pool: Vec<(Entity, &DeterministicSpawn)> = spawn_pool.iter().collect();
+    sorted_spawn_pool.sort_by_key(|e| e.1.index);
+    let rollback_ids = sorted_spawn_pool
+        .into_iter()
+        .map(|(entity, _)| (entity, rip.next_id()))
+        .collect();
+    commands.insert_resource(SpawnPool {
+        rollback_ids,
+        ..default()
+    });
+}
+
+/// bevy_ggrs despawns anything with a [`Rollback`] id that is missing from the
+/// snapshot it loads, which includes whatever we took out of the pool after
+/// that frame.  Those were still in the pool back then, so put them back in
+/// it under the same [`Entity`], which is what Rapier and our peers know them
+/// by.  Exclusive, so nothing can spawn into their slots first.
+pub fn restore_spawn_pool(world: &mut World) {
+    world.resource_scope(|world, pool: Mut<SpawnPool>| {
+        for (&entity, &index) in pool.taken.iter() {
+            if world.get_entity(entity).is_some() {
+                continue;
+            }
+            log::trace!("Putting {:?} back into the pool as {}", entity, index);
+            world
+                .get_or_spawn(entity)
+                .expect("Nothing spawns before the pool is restored")
+                .insert(DeterministicSpawnBundle::new(index));
+        }
+    });
+}
+
+#[allow(clippy::type_complexity)]
+pub fn return_to_pool(
+    mut commands: Commands,
+    mut rapier: ResMut<RapierContext>,
+    mut next_index: ResMut<NextSpawnIndex>,
+    current_frame: Res<CurrentFrame>,
+    returning: Query<(
+        Entity,
+        &Rollback,
+        &ReturnToPool,
+        Option<&RapierRigidBodyHandle>,
+        Option<&RapierColliderHandle>,
+    )>,
+) {
+    // Whatever order the systems that returned these ran in, hand them back
+    // in the same order every time
+    let mut returning: Vec<_> = returning.iter().collect();
+    returning.sort_by_key(|r| r.1.id());
+
+    let RapierContext {
+        bodies,
+        colliders,
+        islands,
+        impulse_joints,
+        multibody_joints,
+        narrow_phase,
+        ..
+    } = &mut *rapier;
+
+    let mut removed_colliders = Vec::new();
+    for (entity, _, returned, body, collider) in returning {
+        // bevy_rapier never hears about this, see `sync_backend_systems`.
+        // Take it out of the physics world ourselves.
+        if let Some(body) = body {
+            if let Some(body) = bodies.get(body.0) {
+                removed_colliders.extend_from_slice(body.colliders());
+            }
+            bodies.remove(
+                body.0,
+                islands,
+                colliders,
+                impulse_joints,
+                multibody_joints,
+                true,
+            );
+        } else if let Some(collider) = collider {
+            colliders.remove(collider.0, islands, bodies, true);
+            removed_colliders.push(collider.0);
+        }
+
+        let mut e = commands.entity(entity);
+        (returned.strip)(&mut e);
+        e.remove::<(ReturnToPool, RapierRigidBodyHandle, RapierColliderHandle)>()
+            .insert(DeterministicSpawn {
+                index: next_index.0,
+                returned: current_frame.0,
+            });
+        log::trace!("Returned {:?} to the pool as {}", entity, next_index.0);
+        next_index.0 += 1;
+    }
+
+    // Left for the next step, Rapier would report every contact these had as
+    // stopped, and bevy_rapier could no longer tell which entity they were.
+    // Only started contacts matter to us, so drop them quietly.
+    narrow_phase.handle_user_changes(
+        Some(islands),
+        &[],
+        &removed_colliders,
+        colliders,
+        bodies,
+        &(),
+    );
+}
+
+/// Takes the [`Rollback`] id back off entities that were handed back to the
+/// pool, once we can no longer roll back to a frame where they were still in
+/// use.  Until then bevy_ggrs has to see them, or loading such a frame would
+/// spawn a new entity in their place.
+pub fn release_rollback_ids(
+    mut commands: Commands,
+    confirmed_frame: Res<ConfirmedFrame>,
+    returned: Query<(Entity, &DeterministicSpawn), With<Rollback>>,
+) {
+    for (entity, spawn) in returned.iter() {
+        // A frame that is confirmed can still be loaded, as the start of the
+        // frame after it
+        if spawn.returned < confirmed_frame.0 {
+            commands.entity(entity).remove::<Rollback>();
+        }
+    }
+}
+
+#[test]
+fn test_return_to_pool() {
+    use bevy::ecs::schedule::Stage;
+
+    fn take(mut spawner: DeterministicSpawner) {
+        spawner.spawn(Transform::default()).unwrap();
+    }
+
+    fn strip(entity: &mut EntityCommands) {
+        entity.remove::<Transform>();
+    }
+
+    let mut world = World::new();
+    let pool: Vec<Entity> = world
+        .spawn_batch((0..3).map(DeterministicSpawnBundle::new))
+        .collect();
+    world.insert_resource(RollbackIdProvider::default());
+    world.insert_resource(RapierContext::default());
+    world.insert_resource(NextSpawnIndex::default());
+    world.insert_resource(CurrentFrame(5));
+    world.insert_resource(ConfirmedFrame(0));
+    SystemStage::single_threaded()
+        .with_system(prepare_spawn_pool)
+        .run(&mut world);
+    SystemStage::single_threaded()
+        .with_system(take)
+        .run(&mut world);
+    assert_eq!(world.get::<Rollback>(pool[0]).map(|r| r.id()), Some(0));
+    assert!(world.get::<DeterministicSpawn>(pool[0]).is_none());
+
+    world.entity_mut(pool[0]).insert(ReturnToPool { strip });
+    let mut pool_systems = SystemStage::single_threaded()
+        .with_system(return_to_pool)
+        .with_system(release_rollback_ids);
+    pool_systems.run(&mut world);
+    let spawn = world.get::<DeterministicSpawn>(pool[0]).unwrap();
+    assert_eq!((spawn.index, spawn.returned), (SPAWN_POOL_SIZE, 5));
+    assert!(world.get::<Transform>(pool[0]).is_none());
+    assert!(world.get::<ReturnToPool>(pool[0]).is_none());
+    assert_eq!(world.resource::<NextSpawnIndex>().0, SPAWN_POOL_SIZE + 1);
+
+    // We could still load the start of frame 5, where it was in use
+    world.insert_resource(ConfirmedFrame(5));
+    pool_systems.run(&mut world);
+    assert!(world.get::<Rollback>(pool[0]).is_some());
+
+    world.insert_resource(ConfirmedFrame(6));
+    pool_systems.run(&mut world);
+    assert!(world.get::<Rollback>(pool[0]).is_none());
+
+    // It queues up behind the entities that never left
+    SystemStage::single_threaded()
+        .with_system(take)
+        .run(&mut world);
+    assert!(world.get::<DeterministicSpawn>(pool[1]).is_none());
+    assert!(world.get::<DeterministicSpawn>(pool[0]).is_some());
+}
@@ -1,6 +1,6 @@
 use crate::{
-    dude::{dude_hp_bar, DudeBundle},
-    dungeon::{Dungeon, TileKind},
+    dude::{dude_dash_bar, dude_hp_bar, DudeBundle},
+    dungeon::{Dungeon, SpawnPointIndex, TileKind},
     prelude::*,
 };
 
@@ -21,9 +21,17 @@ pub fn startup(mut commands: Commands) {
     commands.insert_resource(LocalHandles::default());
     //commands.insert_resource(WrappedSessionType::default());
 
-    // physics toggling
-    commands.insert_resource(EnablePhysicsAfter::default());
-    commands.insert_resource(PhysicsEnabled::default());
+    // round start and match state
+    commands.insert_resource(RoundStart::default());
+    commands.insert_resource(MatchState::default());
+
+    // spawn pool
+    commands.insert_resource(NextSpawnIndex::default());
+
+    // collision events
+    commands.insert_resource(CollisionLog::default());
+    commands.insert_resource(Events::<GameCollision>::default());
+    commands.insert_resource(Events::<GameContactForce>::default());
 
     // network timer
     commands.insert_resource(NetworkStatsTimer(Timer::from_seconds(
@@ -62,28 +70,30 @@ pub fn reset_rapier(
 
     // Add a bit more CCD
     rapier.integration_parameters.max_ccd_substeps = 5;
+}
 
-    if let Ok(context_bytes) = bincode::serialize(rapier.as_ref()) {
-        let rapier_checksum = fletcher16(&context_bytes);
+/// Runs once everything spawned at startup is in the physics world, see
+/// [`crate::add_physics_startup`].  Seeds the ring with frame 0 so there is
+/// always something to go back to, even before the first frame.
+pub fn seed_physics_snapshots(mut commands: Commands, rapier: Res<RapierContext>) {
+    let mut snapshots = PhysicsSnapshots::default();
+    if let Some(rapier_checksum) = snapshots.store(0, 0, rapier.as_ref()) {
         log::trace!("Context hash at init: {}", rapier_checksum);
 
-        commands.insert_resource(PhysicsRollbackState {
-            rapier_state: Some(context_bytes),
-            rapier_checksum,
-        })
+        commands.insert_resource(PhysicsRollbackState { rapier_checksum })
     } else {
         commands.insert_resource(PhysicsRollbackState::default());
     }
+    commands.insert_resource(snapshots);
 }
 
 pub fn respawn_all(
     mut commands: Commands,
-    mut rip: ResMut<RollbackIdProvider>,
-    spawn_pool: Query<(Entity, &DeterministicSpawn)>,
+    mut spawner: DeterministicSpawner,
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
 ) {
-    let mut dungeon = Dungeon::gen(2);
+    let dungeon = Dungeon::gen(2);
     commands.spawn(Camera2dBundle::default());
 
     // Everything must be spawned in the same order, every time,
@@ -94,12 +104,6 @@ pub fn respawn_all(
     // otherwise on the reading end, much like the below sorting of our spawn.
     // WARNING:  This is something on my branch only!  This is in bevy_rapier PR #233
 
-    // Get our entities and sort them by the spawn component index
-    let mut sorted_spawn_pool: Vec<(Entity, &DeterministicSpawn)> = spawn_pool.iter().collect();
-    sorted_spawn_pool.sort_by_key(|e| e.1.index);
-    // Get the Entities in reverse for easy popping
-    let mut sorted_entity_pool: Vec<Entity> = sorted_spawn_pool.iter().map(|p| p.0).rev().collect();
-
     let texture_handle = asset_server.load("tiles.png");
     let texture_atlas = TextureAtlas::from_grid(
         texture_handle,
@@ -115,22 +119,21 @@ pub fn respawn_all(
     let texture_atlas_handle = texture_atlases.add(texture_atlas);
     for (y, row) in dungeon.get_tiles().into_iter().enumerate() {
         for (x, tile) in row.into_iter().enumerate() {
-            let mut e = commands.entity(sorted_entity_pool.pop().unwrap());
-            e.remove::<DeterministicSpawn>();
-
-            e.insert(SpriteSheetBundle {
-                sprite: TextureAtlasSprite::new(match tile.kind {
-                    TileKind::Empty => 0,
-                    TileKind::Floor => 1,
-                    TileKind::Wall => 2,
-                }),
-                texture_atlas: texture_atlas_handle.clone(),
-                transform: Transform {
-                    translation: Vec3::new(x as f32, y as f32, 0.0) * (TILE_SIZE as f32),
+            let mut e = spawner
+                .spawn_static(SpriteSheetBundle {
+                    sprite: TextureAtlasSprite::new(match tile.kind {
+                        TileKind::Empty => 0,
+                        TileKind::Floor => 1,
+                        TileKind::Wall => 2,
+                    }),
+                    texture_atlas: texture_atlas_handle.clone(),
+                    transform: Transform {
+                        translation: Vec3::new(x as f32, y as f32, 0.0) * (TILE_SIZE as f32),
+                        ..default()
+                    },
                     ..default()
-                },
-                ..default()
-            });
+                })
+                .expect("The dungeon does not fit in the spawn pool");
             if matches!(tile.kind, TileKind::Wall) {
                 e.insert(RigidBody::Fixed)
                     .insert(Collider::cuboid(32.0, 32.0))
@@ -138,20 +141,25 @@ pub fn respawn_all(
             }
         }
     }
+    let mut spawn_index = SpawnPointIndex::default();
     for i in 0..=1 {
-        let spawn = dungeon.get_spawn_point();
-        let id = Rollback::new(rip.next_id());
-        let dude = commands
-            .entity(sorted_entity_pool.pop().unwrap())
-            .insert(DudeBundle::new(
+        let spawn = dungeon.get_spawn_point(&mut spawn_index);
+        let dude = spawner
+            .spawn(DudeBundle::new(
                 i,
-                id,
                 asset_server.load("guy.png"),
-                Vec2::new((spawn.0 * TILE_SIZE) as f32, (spawn.1 * TILE_SIZE) as f32),
+                Dungeon::spawn_position(spawn),
                 100,
+                // Armed once the weapons have loaded, see `equip_starting_weapons`
+                Weapon::default(),
             ))
-            .remove::<DeterministicSpawn>()
+            .expect("No room left in the spawn pool for the players")
             .id();
         commands.spawn(dude_hp_bar(dude));
+        commands.spawn(dude_dash_bar(dude));
     }
+
+    // Respawns pick up where we left off
+    commands.insert_resource(spawn_index);
+    commands.insert_resource(dungeon);
 }
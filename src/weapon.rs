@@ -0,0 +1,308 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+
+use crate::prelude::*;
+
+/// One entry in `assets/weapons.ron`
+#[derive(Clone, Debug, Deserialize)]
+pub struct WeaponDefinition {
+    pub name: String,
+    /// Frames between shots
+    pub fire_interval: usize,
+    /// Keeps firing while the trigger is held, rather than once per pull
+    #[serde(default)]
+    pub automatic: bool,
+    pub projectile_speed: f32,
+    /// Angle the pellets of a single shot fan out across
+    pub spread: f32,
+    /// Projectiles per shot
+    pub pellets: usize,
+    /// Damage per projectile
+    pub damage: usize,
+    /// Shots before we have to reload
+    pub magazine: usize,
+    pub reload_frames: usize,
+    /// Half extents of the projectile collider
+    pub projectile_size: (f32, f32),
+    pub texture: String,
+}
+
+impl WeaponDefinition {
+    /// Angles of each pellet in a shot aimed at `angle`, evenly spread rather
+    /// than random so there is nothing to keep in sync
+    pub fn pellet_angles(&self, angle: f32) -> impl Iterator<Item = f32> + '_ {
+        let step = if self.pellets > 1 {
+            self.spread / (self.pellets - 1) as f32
+        } else {
+            0.0
+        };
+        let first = if self.pellets > 1 {
+            angle - self.spread / 2.0
+        } else {
+            angle
+        };
+        (0..self.pellets).map(move |i| first + step * i as f32)
+    }
+}
+
+/// Every weapon there is, in the order of `assets/weapons.ron`.  Never
+/// changes once loaded, so it does not need to be rolled back, but every peer
+/// has to have the same ones.  We send [`Weapons::checksum`] along with our
+/// inputs so a peer with a different file is caught straight away.
+#[derive(Resource, TypeUuid)]
+#[uuid = "5f0c1b8e-3a47-4d2b-9a61-0e7f2c94d3b5"]
+pub struct Weapons {
+    definitions: Vec<WeaponDefinition>,
+    checksum: u16,
+}
+
+impl Weapons {
+    pub fn parse(source: &[u8]) -> Result<Self, ron::error::SpannedError> {
+        let definitions: Vec<WeaponDefinition> = ron::de::from_bytes(source)?;
+        assert!(!definitions.is_empty(), "There are no weapons");
+        Ok(Self {
+            definitions,
+            checksum: fletcher16(source),
+        })
+    }
+
+    /// Of the file they were parsed from, byte for byte
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    /// How many weapon slots there are to pick from
+    pub fn count(&self) -> usize {
+        self.definitions.len()
+    }
+
+    pub fn get(&self, weapon: usize) -> &WeaponDefinition {
+        &self.definitions[weapon]
+    }
+
+    /// Fresh off the rack, with a full magazine
+    pub fn equip(&self, weapon: usize) -> Weapon {
+        Weapon {
+            definition: weapon,
+            cooldown_frames: 0,
+            ammo: self.get(weapon).magazine,
+            reload_frames: 0,
+            trigger_held: false,
+        }
+    }
+}
+
+/// Loads `assets/weapons.ron` into [`Weapons`]
+#[derive(Default)]
+pub struct WeaponsLoader;
+
+impl AssetLoader for WeaponsLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(Weapons::parse(bytes)?));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+/// The weapons while they are still loading, see [`insert_weapons`]
+#[derive(Resource)]
+pub struct WeaponsHandle(Handle<Weapons>);
+
+pub fn load_weapons(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(WeaponsHandle(asset_server.load("weapons.ron")));
+}
+
+/// Moves the weapons out of the asset server and into a [`Weapons`] resource
+/// as soon as they have loaded.  No session starts before then, see
+/// [`crate::network::update_matchbox_socket`].
+pub fn insert_weapons(
+    mut commands: Commands,
+    handle: Option<Res<WeaponsHandle>>,
+    mut assets: ResMut<Assets<Weapons>>,
+) {
+    if let Some(weapons) = handle.and_then(|handle| assets.remove(&handle.0)) {
+        info!("Loaded weapons, checksum {}", weapons.checksum());
+        commands.insert_resource(weapons);
+        commands.remove_resource::<WeaponsHandle>();
+    }
+}
+
+/// Everyone starts out with the first weapon, once there are weapons
+pub fn equip_starting_weapons(weapons: Option<Res<Weapons>>, mut dudes: Query<&mut Weapon>) {
+    if let Some(weapons) = weapons.filter(|weapons| weapons.is_added()) {
+        for mut weapon in dudes.iter_mut() {
+            *weapon = weapons.equip(0);
+        }
+    }
+}
+
+pub struct WeaponsPlugin;
+
+impl Plugin for WeaponsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Weapons>()
+            .init_asset_loader::<WeaponsLoader>()
+            .add_startup_system(load_weapons)
+            .add_system(insert_weapons)
+            .add_system(equip_starting_weapons);
+    }
+}
+
+/// The weapon a dude is holding, and what state it is in.  Rolled back.
+#[derive(Component, Default, Reflect, Clone, Copy, Debug, PartialEq, Eq)]
+#[reflect(Component)]
+pub struct Weapon {
+    /// Index into [`Weapons`]
+    pub definition: usize,
+    /// Frames until we can fire again
+    pub cooldown_frames: usize,
+    /// Shots left in the magazine
+    pub ammo: usize,
+    /// Frames until the magazine is full again, zero unless reloading
+    pub reload_frames: usize,
+    /// Whether the trigger was down last frame, so semi-automatic weapons
+    /// fire once per pull however long it is held
+    pub trigger_held: bool,
+}
+
+impl Weapon {
+    /// Counts down the timers, once per frame
+    pub fn tick(&mut self, definition: &WeaponDefinition) {
+        self.cooldown_frames = self.cooldown_frames.saturating_sub(1);
+        if self.reload_frames > 0 {
+            self.reload_frames -= 1;
+            if self.reload_frames == 0 {
+                self.ammo = definition.magazine;
+            }
+        }
+    }
+
+    /// Starts reloading early, unless the magazine is already full
+    pub fn reload(&mut self, definition: &WeaponDefinition) {
+        if self.reload_frames == 0 && self.ammo < definition.magazine {
+            self.reload_frames = definition.reload_frames;
+        }
+    }
+
+    /// Called every frame with whether the trigger is down.  Takes a shot if
+    /// we can, and starts reloading on the last one.  Returns whether it did.
+    pub fn pull_trigger(&mut self, definition: &WeaponDefinition, held: bool) -> bool {
+        let pulled = held && !self.trigger_held;
+        self.trigger_held = held;
+        if !(pulled || held && definition.automatic) {
+            return false;
+        }
+        if self.cooldown_frames > 0 || self.reload_frames > 0 || self.ammo == 0 {
+            return false;
+        }
+        self.ammo -= 1;
+        self.cooldown_frames = definition.fire_interval;
+        if self.ammo == 0 {
+            self.reload_frames = definition.reload_frames;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+fn weapons() -> Weapons {
+    Weapons::parse(include_bytes!("../assets/weapons.ron")).expect("assets/weapons.ron is broken")
+}
+
+#[test]
+fn test_weapons_parse() {
+    let weapons = weapons();
+    for (i, definition) in weapons.definitions.iter().enumerate() {
+        assert!(definition.pellets > 0, "{} fires nothing", definition.name);
+        assert!(definition.magazine > 0, "{} holds nothing", definition.name);
+        assert_eq!(weapons.equip(i).ammo, definition.magazine);
+    }
+}
+
+#[test]
+fn test_weapon_fire_and_reload() {
+    let weapons = weapons();
+    let definition = weapons.get(0);
+    let mut weapon = weapons.equip(0);
+
+    for shot in 0..definition.magazine {
+        assert!(weapon.pull_trigger(definition, true), "shot {}", shot);
+        // Clicking again straight away does nothing
+        assert!(!weapon.pull_trigger(definition, false));
+        assert!(!weapon.pull_trigger(definition, true));
+        for _ in 0..definition.fire_interval {
+            weapon.tick(definition);
+        }
+        weapon.pull_trigger(definition, false);
+    }
+    assert_eq!(weapon.ammo, 0);
+    assert!(!weapon.pull_trigger(definition, true));
+
+    for _ in 0..definition.reload_frames {
+        weapon.tick(definition);
+    }
+    assert_eq!(weapon.ammo, definition.magazine);
+    weapon.pull_trigger(definition, false);
+    assert!(weapon.pull_trigger(definition, true));
+
+    // Reloading early tops the magazine back up
+    weapon.reload(definition);
+    for _ in 0..definition.reload_frames {
+        weapon.tick(definition);
+    }
+    assert_eq!(weapon.ammo, definition.magazine);
+    weapon.reload(definition);
+    assert_eq!(weapon.reload_frames, 0);
+}
+
+#[test]
+fn test_weapon_trigger_held() {
+    let mut definition = weapons().get(0).clone();
+    definition.fire_interval = 3;
+    definition.magazine = 100;
+
+    let frames_fired = |definition: &WeaponDefinition| {
+        let mut weapon = Weapon {
+            ammo: definition.magazine,
+            ..default()
+        };
+        (0..10)
+            .filter(|_| {
+                weapon.tick(definition);
+                weapon.pull_trigger(definition, true)
+            })
+            .count()
+    };
+
+    definition.automatic = false;
+    assert_eq!(frames_fired(&definition), 1);
+    definition.automatic = true;
+    assert_eq!(frames_fired(&definition), 4);
+}
+
+#[test]
+fn test_pellet_angles() {
+    let mut definition = weapons().get(0).clone();
+    definition.pellets = 1;
+    definition.spread = 0.5;
+    assert_eq!(definition.pellet_angles(1.0).collect::<Vec<_>>(), vec![1.0]);
+
+    definition.pellets = 3;
+    assert_eq!(
+        definition.pellet_angles(1.0).collect::<Vec<_>>(),
+        vec![0.75, 1.0, 1.25]
+    );
+}
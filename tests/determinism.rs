@@ -0,0 +1,406 @@
+//! Replays a recorded input script through our rollback schedule, headless,
+//! and checks the physics checksum of every frame against a recording.  The
+//! same test runs natively and as wasm, so any float math that differs
+//! between the two shows up here instead of as a desync between peers.
+//!
+//! Natively: `cargo test --test determinism`
+//!
+//! As wasm, under node with `wasm-bindgen-test-runner` installed:
+//! `CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUNNER=wasm-bindgen-test-runner cargo test --target wasm32-unknown-unknown --features web --test determinism`
+//!
+//! After a deliberate change to the simulation, record new checksums with
+//! `BLESS_CHECKSUMS=1 cargo test --test determinism`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use bevy::{
+    asset::{AssetIo, AssetIoError, AssetPlugin, Metadata},
+    transform::TransformPlugin,
+    utils::BoxedFuture,
+};
+use bevy_ggrs_rapier_example::{
+    add_physics_startup, bullet::Projectile, ggrs_plugin, prelude::*, rapier_configuration,
+    rollback_schedule,
+};
+
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test;
+
+const INPUT_SCRIPT: &str = include_str!("determinism/inputs.txt");
+const EXPECTED_CHECKSUMS: &str = include_str!("determinism/checksums.txt");
+const WEAPONS: &[u8] = include_bytes!("../assets/weapons.ron");
+
+/// How far the sync test session rolls back every frame, so the script is
+/// also resimulated the way a real session would
+const CHECK_DISTANCE: usize = 2;
+
+/// Per-player inputs for every frame, expanded from the script
+#[derive(Resource)]
+struct InputScript(Vec<[PackedInput; NUM_PLAYERS]>);
+
+impl InputScript {
+    /// Every line is a number of frames followed by the buttons, angle and
+    /// movement of each player.  `#` starts a comment.
+    fn parse(script: &str) -> Self {
+        let mut frames = Vec::new();
+        for line in script.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let count: usize = fields.next().unwrap().parse().expect("Bad frame count");
+            let mut field = || fields.next().expect("Missing player input");
+            let mut inputs = [PackedInput::default(); NUM_PLAYERS];
+            for input in inputs.iter_mut() {
+                let buttons: u8 = field().parse().expect("Bad buttons");
+                let angle: u64 = field().parse().expect("Bad angle");
+                let x: i8 = field().parse().expect("Bad movement");
+                let y: i8 = field().parse().expect("Bad movement");
+                *input = script_input(buttons, angle, [x, y]);
+            }
+            frames.extend(std::iter::repeat_n(inputs, count));
+        }
+        Self(frames)
+    }
+}
+
+/// Builds an input out of whole steps, so it goes over the wire unchanged
+fn script_input(buttons: u8, angle: u64, movement: [i8; 2]) -> PackedInput {
+    let steps = UnitVec2::<8>::STEPS as f32;
+    PlayerInput {
+        angle: Angle::<10>::decode(angle),
+        movement: Vec2::new(movement[0] as f32, movement[1] as f32) / steps,
+        primary: buttons & 1 != 0,
+        secondary: buttons & 2 != 0,
+        reload: buttons & 4 != 0,
+        weapon: 0,
+    }
+    .into()
+}
+
+/// First checksum we saw for each frame
+#[derive(Default, Resource)]
+struct RecordedChecksums(BTreeMap<Frame, u16>);
+
+/// How many bullets were flying at the end of each frame, the first time
+/// around
+#[derive(Default, Resource)]
+struct RecordedProjectiles(BTreeMap<Frame, usize>);
+
+fn scripted_input(
+    handle: In<PlayerHandle>,
+    script: Res<InputScript>,
+    current_frame: Res<CurrentFrame>,
+) -> GGRSInput {
+    // The input is for the frame we are about to simulate
+    let frame = current_frame.0 as usize + 1;
+    GGRSInput {
+        input: script
+            .0
+            .get(frame)
+            .map_or(PackedInput::default(), |inputs| inputs[handle.0]),
+        last_confirmed_hash: 0,
+        weapons_checksum: 0,
+        last_confirmed_frame: ggrs::NULL_FRAME,
+    }
+}
+
+/// Resimulating a frame must give the same checksum as the first time around
+fn record_checksum(
+    current_frame: Res<CurrentFrame>,
+    physics: Res<PhysicsRollbackState>,
+    mut recorded: ResMut<RecordedChecksums>,
+) {
+    let checksum = *recorded
+        .0
+        .entry(current_frame.0)
+        .or_insert(physics.rapier_checksum);
+    assert_eq!(
+        checksum, physics.rapier_checksum,
+        "Frame {} changed on resimulation",
+        current_frame.0
+    );
+}
+
+/// The stage runs as many frames as it is owed, and a debug build can fall
+/// further behind with every one of them.  Stop the session at the end of the
+/// script so the stage lets go instead of running on forever.
+fn stop_after_script(
+    mut commands: Commands,
+    script: Res<InputScript>,
+    current_frame: Res<CurrentFrame>,
+) {
+    if current_frame.0 as usize >= script.0.len() {
+        commands.remove_resource::<Session<GGRSConfig>>();
+    }
+}
+
+fn record_projectiles(
+    current_frame: Res<CurrentFrame>,
+    projectiles: Query<&Projectile>,
+    mut recorded: ResMut<RecordedProjectiles>,
+) {
+    recorded
+        .0
+        .entry(current_frame.0)
+        .or_insert_with(|| projectiles.iter().count());
+}
+
+/// We never want to load anything, the simulation does not depend on assets
+struct NoAssetIo;
+
+impl AssetIo for NoAssetIo {
+    fn load_path<'a>(&'a self, path: &'a Path) -> BoxedFuture<'a, Result<Vec<u8>, AssetIoError>> {
+        Box::pin(async move { Err(AssetIoError::NotFound(path.to_path_buf())) })
+    }
+
+    fn read_directory(
+        &self,
+        path: &Path,
+    ) -> Result<Box<dyn Iterator<Item = std::path::PathBuf>>, AssetIoError> {
+        Err(AssetIoError::NotFound(path.to_path_buf()))
+    }
+
+    fn get_metadata(&self, path: &Path) -> Result<Metadata, AssetIoError> {
+        Err(AssetIoError::NotFound(path.to_path_buf()))
+    }
+
+    fn watch_path_for_changes(&self, _path: &Path) -> Result<(), AssetIoError> {
+        Ok(())
+    }
+
+    fn watch_for_changes(&self) -> Result<(), AssetIoError> {
+        Ok(())
+    }
+}
+
+/// Everything the rollback schedule needs, without a window, renderer or
+/// network
+fn build_app(script: InputScript) -> App {
+    let mut app = App::new();
+
+    // Same as the game, the pool has to come first
+    let _ = app
+        .world
+        .spawn_batch((0..SPAWN_POOL_SIZE).map(DeterministicSpawnBundle::new))
+        .collect::<Vec<Entity>>();
+
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        // Our own server goes in first, so the plugin does not make one that
+        // reads from disk
+        .insert_resource(AssetServer::new(NoAssetIo))
+        .add_plugin(AssetPlugin::default())
+        .add_asset::<Image>()
+        .add_asset::<TextureAtlas>()
+        .insert_resource(script)
+        .insert_resource(RecordedChecksums::default())
+        .insert_resource(RecordedProjectiles::default())
+        .insert_resource(MatchRules::default())
+        // Nothing to wait for, the weapons are here from the start
+        .insert_resource(Weapons::parse(WEAPONS).expect("assets/weapons.ron is broken"))
+        .add_system(equip_starting_weapons)
+        .insert_resource(RollbackCounters::default())
+        .insert_resource(RollbackDepthHistogram::default())
+        .add_plugin(CosmeticEffectsPlugin)
+        .add_startup_system(startup)
+        .add_startup_system_to_stage(StartupStage::PreStartup, prepare_spawn_pool)
+        .add_startup_system(reset_rapier)
+        .add_startup_system(respawn_all);
+    add_physics_startup(&mut app);
+
+    let schedule = rollback_schedule()
+        .with_system_in_stage(CHECKSUM_SYSTEMS, record_checksum.after(save_rapier_context))
+        .with_system_in_stage(CHECKSUM_SYSTEMS, record_projectiles)
+        .with_system_in_stage(CHECKSUM_SYSTEMS, stop_after_script);
+    ggrs_plugin()
+        // Run frames as fast as we can, the timestep is fixed regardless
+        .with_update_frequency(10_000)
+        .with_input_system(scripted_input)
+        .with_rollback_schedule(schedule)
+        .build(&mut app);
+
+    app.add_plugin(
+        RapierPhysicsPlugin::<NoUserData>::default()
+            .with_physics_scale(100.)
+            .with_default_system_setup(false),
+    )
+    .insert_resource(rapier_configuration());
+
+    app
+}
+
+fn start_session(app: &mut App, check_distance: usize) {
+    let mut builder = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(NUM_PLAYERS)
+        .with_max_prediction_window(MAX_PREDICTION)
+        .with_check_distance(check_distance);
+    for handle in 0..NUM_PLAYERS {
+        builder = builder
+            .add_player(PlayerType::Local, handle)
+            .expect("Invalid player added.");
+    }
+    let session = builder
+        .start_synctest_session()
+        .expect("Session could not be created.");
+
+    app.insert_resource(LocalHandles {
+        handles: (0..NUM_PLAYERS).collect(),
+    })
+    .insert_resource(Session::SyncTestSession(session));
+}
+
+/// Ticks the session until frame `frame` has been simulated
+fn run_until(app: &mut App, frame: Frame) {
+    while app
+        .world
+        .resource::<RecordedChecksums>()
+        .0
+        .range(frame..)
+        .next()
+        .is_none()
+    {
+        app.update();
+    }
+}
+
+/// Runs the whole script, rolling back `check_distance` frames every frame,
+/// and returns the checksum of every frame in it
+fn replay(script: InputScript, check_distance: usize) -> BTreeMap<Frame, u16> {
+    let frames = script.0.len() as Frame;
+    let mut app = build_app(script);
+
+    // Get through startup before the session starts ticking
+    app.update();
+    start_session(&mut app, check_distance);
+    run_until(&mut app, frames);
+
+    let mut recorded = std::mem::take(&mut app.world.resource_mut::<RecordedChecksums>().0);
+    recorded.retain(|frame, _| *frame < frames);
+    recorded
+}
+
+fn parse_checksums(checksums: &str) -> BTreeMap<Frame, u16> {
+    checksums
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (frame, checksum) = line.split_once(' ').expect("Bad checksum line");
+            (
+                frame.parse().expect("Bad frame"),
+                u16::from_str_radix(checksum.trim(), 16).expect("Bad checksum"),
+            )
+        })
+        .collect()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn bless(recorded: &BTreeMap<Frame, u16>) -> bool {
+    if std::env::var_os("BLESS_CHECKSUMS").is_none() {
+        return false;
+    }
+
+    let mut out = String::from("# frame checksum, recorded by tests/determinism.rs\n");
+    for (frame, checksum) in recorded {
+        out += &format!("{} {:04x}\n", frame, checksum);
+    }
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/determinism/checksums.txt"
+    );
+    std::fs::write(path, out).expect("Could not write checksums");
+    true
+}
+
+#[cfg(target_arch = "wasm32")]
+fn bless(_recorded: &BTreeMap<Frame, u16>) -> bool {
+    false
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn test_replay_matches_recorded_checksums() {
+    let recorded = replay(InputScript::parse(INPUT_SCRIPT), CHECK_DISTANCE);
+    if bless(&recorded) {
+        return;
+    }
+
+    let expected = parse_checksums(EXPECTED_CHECKSUMS);
+    assert!(
+        !expected.is_empty(),
+        "No checksums recorded yet, run with BLESS_CHECKSUMS=1 on native first"
+    );
+    for (frame, checksum) in &expected {
+        assert_eq!(
+            recorded.get(frame),
+            Some(checksum),
+            "Checksum mismatch on frame {}",
+            frame
+        );
+    }
+    assert_eq!(recorded.len(), expected.len(), "Script length changed");
+}
+
+/// Rolling back into the countdown, all the way to the first frame, must end
+/// up exactly where never rolling back at all does
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn test_rollbacks_to_early_frames_are_safe() {
+    // Everyone mashes buttons through the countdown, and a bit past it
+    let frames = (COUNTDOWN_FRAMES + FPS as Frame) as usize;
+    let inputs = [
+        script_input(1, 512, [127, 127]),
+        script_input(1, 256, [-127, 0]),
+    ];
+    let script = || InputScript(vec![inputs; frames]);
+
+    let reference = replay(script(), 0);
+    for check_distance in 1..MAX_PREDICTION {
+        assert_eq!(
+            replay(script(), check_distance),
+            reference,
+            "Rolling back {} frames changed the outcome",
+            check_distance
+        );
+    }
+}
+
+/// The pellets of a shot all start out on top of each other, and must not
+/// take each other out before they get anywhere
+#[cfg_attr(not(target_arch = "wasm32"), test)]
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+fn test_shotgun_pellets_survive_their_first_step() {
+    let weapons = Weapons::parse(WEAPONS).unwrap();
+    let shotgun = 1;
+    assert_eq!(weapons.get(shotgun).name, "Shotgun");
+    let with_shotgun = |buttons| {
+        PlayerInput {
+            weapon: shotgun as u32,
+            ..script_input(buttons, 512, [0, 0]).into()
+        }
+        .into()
+    };
+
+    // Wait out the countdown, then player 0 switches to the shotgun and fires
+    // it once
+    let idle = with_shotgun(0);
+    let mut frames = vec![[idle; NUM_PLAYERS]; COUNTDOWN_FRAMES as usize + 1];
+    frames.push([with_shotgun(1), idle]);
+    let fired = frames.len() as Frame - 1;
+
+    let mut app = build_app(InputScript(frames));
+    app.update();
+    start_session(&mut app, 0);
+
+    // The pellets were stepped once on the frame they were fired, and the
+    // next frame is the first to look at what they touched.  Any further and
+    // they could reach a wall.
+    run_until(&mut app, fired + 1);
+
+    let recorded = &app.world.resource::<RecordedProjectiles>().0;
+    assert_eq!(recorded[&fired], weapons.get(shotgun).pellets);
+    assert_eq!(recorded[&(fired + 1)], weapons.get(shotgun).pellets);
+}